@@ -1,3 +1,4 @@
+use obrah::build::BuildOptions;
 use obrah::data;
 use obrah::kernel;
 use obrah::runtime;
@@ -17,22 +18,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let mut env = runtime::Env::new(0, 0)?; // fix this with the right device - run example get_gpus to see all devices and platforms.
-    env.use_kernel("examples/add_one_kernel.cl")?
-        .program()?
+    let mut kern = env
+        .use_kernel("examples/add_one_kernel.cl")?
+        .program(&BuildOptions::new())?
         .make_kernel("add_one")?;
 
     let mut b = vec![0.0f32];
-    let mut bbuf = data::Buffer::new(&mut env, &mut b);
-    bbuf.to(&mut env);
+    let mut bbuf = data::Buffer::new(&mut env, &b)?;
+    bbuf.to(&b, &mut env)?;
 
     let a = floaty;
 
-    kernel::setarg_scalar(&env, &a, 0);
-    kernel::setarg(&env, &bbuf, 1)?;
+    kernel::setarg_scalar(&mut kern, &a, 0)?;
+    kernel::setarg(&mut kern, &bbuf, 1)?;
 
-    kernel::run_kernel(&mut env, 1, 1);
+    kernel::run_kernel(&mut env, &kern, 1)?;
 
-    bbuf.from(&mut b, &mut env);
+    bbuf.from(&mut b, &mut env)?;
 
     println!("Result: {:?}", b[0]);
     Ok(())
@@ -1,5 +1,6 @@
-use obrah::kernel::{get_devices};
+use obrah::device::get_devices;
 
-fn main() {
-    get_devices();
-}
\ No newline at end of file
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    get_devices()?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use obrah::build::BuildOptions;
 use obrah::data::*;
 use obrah::kernel::*;
 use obrah::runtime::*;
@@ -40,10 +41,10 @@ fn read_input(inputmsg: &str) -> f32 {
 
     let trimmed = input.trim();
 
-    return match trimmed.parse::<f32>() {
+    match trimmed.parse::<f32>() {
         Ok(val) => val,
         Err(_) => panic!("Please enter a valid float."),
-    };
+    }
 }
 
 fn read_texture(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -56,12 +57,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     const WIDTH: usize = 1000;
     const HEIGHT: usize = 1000;
     let mut env = Env::new(0, 0)?; // fix this with the right device - run example get_gpus to see all devices and platforms.
-    env.use_kernel("examples/raytrace_kernel.cl")?
-        .program()?
+    let mut kernel = env
+        .use_kernel("examples/raytrace_kernel.cl")?
+        .program(&BuildOptions::new())?
         .make_kernel("raytrace")?;
 
     let mut data = vec![0.0f32; WIDTH * HEIGHT * 4]; // we are going to make an empty buffer 1000 * 1000, and multiply by 4 for all 4 channels.
-    let mut data_buf = Buffer::new(&mut env, &data);
+    let mut data_buf = Buffer::new(&mut env, &data)?;
 
     let mut input = String::new();
 
@@ -76,7 +78,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut sphere = [700.0f32, 500.0, 100.0, 100.0];
     let mut light = [500.0f32, 400.0, 300.0];
 
-    if input.to_lowercase() == String::from("y") {
+    if input.to_lowercase() == "y" {
         let sphere_x = read_input("Enter sphere x: ");
         let sphere_y = read_input("Enter sphere y: ");
         let sphere_z = read_input("Enter sphere z: ");
@@ -88,37 +90,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         light = [light_x, light_y, light_z];
     }
 
-    setarg(&env, &data_buf, 0)?; // our first argument is the empty buffer.
-    setarg_scalar(&env, &WIDTH, 1); // the second argument is the width.
-    setarg_scalar(&env, &HEIGHT, 2); // the third argument is the height.
-    setarg_scalar(&env, &sphere, 3); // the fourth argument - THE SPHERE.
-    setarg_scalar(&env, &light, 4); // the light is fifth
+    setarg(&mut kernel, &data_buf, 0)?; // our first argument is the empty buffer.
+    setarg_scalar(&mut kernel, &WIDTH, 1)?; // the second argument is the width.
+    setarg_scalar(&mut kernel, &HEIGHT, 2)?; // the third argument is the height.
+    setarg_scalar(&mut kernel, &sphere, 3)?; // the fourth argument - THE SPHERE.
+    setarg_scalar(&mut kernel, &light, 4)?; // the light is fifth
 
     let pixels = read_texture("texture.raw")?;
     let mut tex = Vec::with_capacity(pixels.len());
     for &b in &pixels {
         tex.push(b as f32 / 255.0);
     }
-    let mut tex_buf = Buffer::new(&mut env, &tex);
-    tex_buf.to(&mut env);
-    setarg(&env, &tex_buf, 5)?; // sphere texture
+    let tex_buf: Buffer<f32> = BufferBuilder::new()
+        .read_only()
+        .host_no_access()
+        .copy_from(&tex)
+        .build(&mut env)?; // made already filled in, and only ever read by the kernel
+    setarg(&mut kernel, &tex_buf, 5)?; // sphere texture
 
     let pixels = read_texture("ground.raw")?;
     let mut tex = Vec::with_capacity(pixels.len());
     for &b in &pixels {
         tex.push(b as f32 / 255.0);
     }
-    let mut tex_buf = Buffer::new(&mut env, &tex);
-    tex_buf.to(&mut env);
-    setarg(&env, &tex_buf, 6)?; // ground texture
+    let tex_buf: Buffer<f32> = BufferBuilder::new()
+        .read_only()
+        .host_no_access()
+        .copy_from(&tex)
+        .build(&mut env)?;
+    setarg(&mut kernel, &tex_buf, 6)?; // ground texture
 
-    setarg_scalar(&env, &1, 7); // shadow the ground
+    setarg_scalar(&mut kernel, &1, 7)?; // shadow the ground
 
     println!("Starting kernel execution...");
-    run_kernel(&mut env, WIDTH, HEIGHT); // we are going to run the kernel at 1k by 1k resolution.
+    run_kernel(&mut env, &kernel, (WIDTH, HEIGHT))?; // we are going to run the kernel at 1k by 1k resolution.
     println!("Kernel execution finished.");
 
-    data_buf.from(&mut data, &mut env); // now, we are going to retrieve the data from the buffer.
+    data_buf.from(&mut data, &mut env)?; // now, we are going to retrieve the data from the buffer.
 
     save_ppm("out.ppm", WIDTH, HEIGHT, &data).unwrap(); // all that is left is to save the ppm file.
 
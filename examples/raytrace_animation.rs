@@ -1,3 +1,4 @@
+use obrah::build::BuildOptions;
 use obrah::data::*;
 use obrah::kernel::*;
 use obrah::runtime::*;
@@ -14,6 +15,7 @@ fn lerp(start: f32, end: f32, total: i32) -> Vec<f32> {
     last
 }
 
+#[allow(clippy::too_many_arguments)]
 fn lerp_sphere(
     start_x: f32,
     end_x: f32,
@@ -115,28 +117,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let ffmpeg_stdin = ffmpeg.stdin.as_mut().unwrap();
     let mut env = Env::new(0, 0)?; // fix this with the right device - run example get_gpus to see all devices and platforms.
-    env.use_kernel("examples/raytrace_kernel.cl")? // we want this kernel
-        .program()? // program it
+    let mut kernel = env
+        .use_profiling()? // time everything, so we can see where it goes
+        .use_kernel("examples/raytrace_kernel.cl")? // we want this kernel
+        .program(&BuildOptions::new())? // program it
         .make_kernel("raytrace")?; // make sure it is the same name as in the kernel
 
     let mut data = vec![0.0f32; WIDTH * HEIGHT * 4]; // we are going to make an empty buffer 1920 * 1080, and multiply by 4 for all 4 channels of RGBA.
-    let mut data_buf = Buffer::new(&mut env, &data);
+    let mut data_buf = Buffer::new(&mut env, &data)?;
 
-    setarg(&env, &data_buf, 0)?; // our first argument is the empty buffer.
+    setarg(&mut kernel, &data_buf, 0)?; // our first argument is the empty buffer.
 
-    setarg_scalar(&env, &WIDTH, 1); // the second argument is the width.
-    setarg_scalar(&env, &HEIGHT, 2); // the third argument is the height.
+    setarg_scalar(&mut kernel, &WIDTH, 1)?; // the second argument is the width.
+    setarg_scalar(&mut kernel, &HEIGHT, 2)?; // the third argument is the height.
     let tex = read_texture("texture.raw")?;
-    let mut tex_buf = Buffer::new(&mut env, &tex);
-    tex_buf.to(&mut env);
-    setarg(&env, &tex_buf, 5)?; // sphere texture
+    let tex_buf: Buffer<f32> = BufferBuilder::new()
+        .read_only()
+        .host_no_access()
+        .copy_from(&tex)
+        .build(&mut env)?; // made already filled in, and only ever read by the kernel
+    setarg(&mut kernel, &tex_buf, 5)?; // sphere texture
 
     let tex = read_texture("ground.raw")?;
-    let mut tex_buf = Buffer::new(&mut env, &tex);
-    tex_buf.to(&mut env);
-    setarg(&env, &tex_buf, 6)?; // ground texture (commented out for now, uncomment line 73 and comment line 74 in the raytracer if you want a texture)
+    let tex_buf: Buffer<f32> = BufferBuilder::new()
+        .read_only()
+        .host_no_access()
+        .copy_from(&tex)
+        .build(&mut env)?;
+    setarg(&mut kernel, &tex_buf, 6)?; // ground texture (commented out for now, uncomment line 73 and comment line 74 in the raytracer if you want a texture)
 
-    setarg_scalar(&env, &1, 7); // shadow the ground
+    setarg_scalar(&mut kernel, &1, 7)?; // shadow the ground
 
     // now we are going to make an animation render thing
     let mut buf_frames: Vec<u8> = Vec::with_capacity(WIDTH * HEIGHT * 3 * 180); // poor ram. i apologise sincerely (not)
@@ -155,13 +165,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let time = std::time::Instant::now();
     for i in 1..=180 {
         let light = full_light[(i - 1) as usize];
-        setarg_scalar(&env, &light, 4);
+        setarg_scalar(&mut kernel, &light, 4)?;
         let sphere = full_sphere[(i - 1) as usize];
-        setarg_scalar(&env, &sphere, 3);
+        setarg_scalar(&mut kernel, &sphere, 3)?;
 
-        run_kernel(&mut env, WIDTH, HEIGHT); // we are going to run the kernel at 1080p
+        run_kernel(&mut env, &kernel, (WIDTH, HEIGHT))?; // we are going to run the kernel at 1080p
 
-        data_buf.from(&mut data, &mut env); // now, we are going to retrieve the data from the buffer.
+        data_buf.from(&mut data, &mut env)?; // now, we are going to retrieve the data from the buffer.
         // little challenge for anyone who wants to test themselves -
         // could you change this code to instead handle transform into bytes on the kernel instead?
         // remember - it takes less than 3 seconds to render, even on an igpu.
@@ -185,6 +195,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     ffmpeg.wait()?;
 
     println!("{:?} seconds to render video.", time.elapsed());
+    println!("{}", env.profile_report()?); // GPU time per kernel and transfer
 
     println!("Output saved.");
     Ok(())
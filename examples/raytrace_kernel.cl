@@ -12,7 +12,9 @@ __kernel void raytrace(__global float4 *image_buf, const int width,
       y >= height) // this shouldn't happen, but if it does, ignore.
     return;
 
-  int pixel = y * width + x; // calculate pixel offset
+  // calculate pixel offset. it's from the first row we were given, not the top of the image,
+  // so the image can be split between devices with a different image_buf for each part
+  int pixel = (y - get_global_offset(1)) * width + x;
 
   float brightness = 0.0f; // brightness starts off empty
 
@@ -1,3 +1,4 @@
+use obrah::build::BuildOptions;
 use obrah::data::Buffer;
 use obrah::kernel::*;
 use obrah::runtime::Env;
@@ -5,8 +6,9 @@ use obrah::runtime::Env;
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Setup - Initialise kernel & queue.
     let mut env = Env::new(0, 0)?;
-    env.use_kernel("examples/vecadd_kernel.cl")?
-        .program()?
+    let mut kernel = env
+        .use_kernel("examples/vecadd_kernel.cl")?
+        .program(&BuildOptions::new())?
         .make_kernel("vec_add")?;
 
     // Create data.
@@ -15,24 +17,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut result = vec![0.0f32; a.len()];
 
     // Buffers.
-    let mut buf_a = Buffer::new(&mut env, &a);
-    let mut buf_b = Buffer::new(&mut env, &b);
-    let mut buf_result = Buffer::new(&mut env, &result);
+    let mut buf_a = Buffer::new(&mut env, &a)?;
+    let mut buf_b = Buffer::new(&mut env, &b)?;
+    let mut buf_result = Buffer::new(&mut env, &result)?;
 
     // Send data to GPU.
-    buf_a.to(&mut env);
-    buf_b.to(&mut env);
+    buf_a.to(&a, &mut env)?;
+    buf_b.to(&b, &mut env)?;
 
     // Set kernel arguments.
-    setarg(&env, &buf_a, 0)?;
-    setarg(&env, &buf_b, 1)?;
-    setarg(&env, &buf_result, 2)?;
+    setarg(&mut kernel, &buf_a, 0)?;
+    setarg(&mut kernel, &buf_b, 1)?;
+    setarg(&mut kernel, &buf_result, 2)?;
 
     // Run kernel.
-    run_kernel(&mut env, a.len(), 1);
+    run_kernel(&mut env, &kernel, a.len())?;
 
     // Read result.
-    buf_result.from(&mut result, &mut env);
+    buf_result.from(&mut result, &mut env)?;
 
     // Output result.
     println!("Result: {:#?}", result);
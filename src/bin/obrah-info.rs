@@ -0,0 +1,262 @@
+//! obrah-info prints what OpenCL platforms and devices a machine has, like clinfo.
+//!
+//! ```text
+//! obrah-info                      report every platform and device
+//! obrah-info --json               the same, as JSON
+//! obrah-info --check kernel.cl    also build kernel.cl on every device
+//! ```
+//!
+//! With --check, it exits with 1 if the kernel doesn't build on some device.
+
+use obrah::build::BuildOptions;
+use obrah::device::{Device, Platform, platforms};
+use obrah::program::Program;
+use obrah::runtime::{ClError, Env};
+use std::fmt::Write;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: obrah-info [--json] [--check <kernel.cl>]";
+
+/// What building the --check kernel on a device gave.
+enum Check {
+    Built,
+    Failed(String),
+}
+
+fn main() -> ExitCode {
+    let mut json = false;
+    let mut check = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--check" => match args.next() {
+                Some(path) => check = Some(path),
+                None => return usage(),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => return usage(),
+        }
+    }
+
+    match run(json, check.as_deref()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::from(2)
+}
+
+/// run() prints the report. Gives back false if the check kernel failed to build anywhere.
+fn run(json: bool, check: Option<&str>) -> Result<bool, Box<dyn std::error::Error>> {
+    let source = check.map(std::fs::read_to_string).transpose()?;
+    let platforms = platforms()?;
+    let mut report = Vec::new();
+    for platform in &platforms {
+        let mut devices = Vec::new();
+        for device in platform.devices()? {
+            let result = source.as_deref().map(|source| build(&device, source));
+            devices.push((device, result));
+        }
+        report.push((platform, devices));
+    }
+
+    let passed = report
+        .iter()
+        .flat_map(|(_, devices)| devices)
+        .all(|(_, check)| !matches!(check, Some(Check::Failed(_))));
+    if json {
+        println!("{}", to_json(&report));
+    } else {
+        print!("{}", to_text(&report));
+    }
+    Ok(passed)
+}
+
+/// build() builds the check kernel on one device.
+fn build(device: &Device, source: &str) -> Check {
+    let built = Env::from_device(device)
+        .and_then(|env| Program::new(&env, &[source], &BuildOptions::new()).map(|_| ()));
+    match built {
+        Ok(()) => Check::Built,
+        Err(ClError::BuildFailed(log)) => Check::Failed(log.to_string()),
+        Err(e) => Check::Failed(e.to_string()),
+    }
+}
+
+type Report<'a> = [(&'a Platform, Vec<(Device, Option<Check>)>)];
+
+fn to_text(report: &Report) -> String {
+    let mut out = String::new();
+    if report.is_empty() {
+        out.push_str("No OpenCL platforms found.\n");
+    }
+    for (p_idx, (platform, devices)) in report.iter().enumerate() {
+        let _ = writeln!(out, "Platform {p_idx}: {}", platform.name());
+        let rows = [
+            ("Vendor", platform.vendor()),
+            ("Version", platform.version()),
+            ("Profile", platform.profile()),
+            ("Extensions", platform.extensions().join(" ")),
+        ];
+        for (name, value) in rows {
+            let _ = writeln!(out, "  {name:<24}{value}");
+        }
+        for (d_idx, (device, check)) in devices.iter().enumerate() {
+            let _ = writeln!(out, "  Device {d_idx}: {}", device.name());
+            let sizes = device.max_work_item_sizes();
+            let rows = [
+                ("Type", device.device_type().to_string()),
+                ("Vendor", device.vendor()),
+                ("Version", device.version()),
+                ("Driver version", device.driver_version()),
+                ("OpenCL C version", device.opencl_c_version()),
+                ("Compute units", device.compute_units().to_string()),
+                ("Max clock", format!("{} MHz", device.clock_mhz())),
+                ("Global memory", mib(device.global_mem())),
+                ("Local memory", kib(device.local_mem())),
+                ("Constant memory", kib(device.constant_mem())),
+                ("Max allocation", mib(device.max_alloc())),
+                (
+                    "Max work-group size",
+                    device.max_work_group_size().to_string(),
+                ),
+                ("Max work-item sizes", join(&sizes, " x ")),
+                ("Image support", yes_no(device.image_support())),
+                ("Double precision", yes_no(device.fp64())),
+                ("Extensions", device.extensions().join(" ")),
+            ];
+            for (name, value) in rows {
+                let _ = writeln!(out, "    {name:<22}{value}");
+            }
+            match check {
+                Some(Check::Built) => {
+                    let _ = writeln!(out, "    {:<22}builds", "Check");
+                }
+                Some(Check::Failed(log)) => {
+                    let _ = writeln!(out, "    {:<22}FAILED", "Check");
+                    for line in log.lines() {
+                        let _ = writeln!(out, "      {line}");
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    out
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{} MiB", bytes >> 20)
+}
+
+fn kib(bytes: u64) -> String {
+    format!("{} KiB", bytes >> 10)
+}
+
+fn join(values: &[usize], sep: &str) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(sep)
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn to_json(report: &Report) -> String {
+    let platforms: Vec<String> = report
+        .iter()
+        .map(|(platform, devices)| {
+            let devices: Vec<String> = devices
+                .iter()
+                .map(|(device, check)| device_json(device, check.as_ref()))
+                .collect();
+            format!(
+                "{{\"name\":{},\"vendor\":{},\"version\":{},\"profile\":{},\"extensions\":{},\"devices\":[{}]}}",
+                string(&platform.name()),
+                string(&platform.vendor()),
+                string(&platform.version()),
+                string(&platform.profile()),
+                strings(&platform.extensions()),
+                devices.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"platforms\":[{}]}}", platforms.join(","))
+}
+
+fn device_json(device: &Device, check: Option<&Check>) -> String {
+    let sizes = device.max_work_item_sizes();
+    let mut out = format!(
+        "{{\"name\":{},\"type\":{},\"vendor\":{},\"version\":{},\"driver_version\":{},\
+         \"opencl_c_version\":{},\"compute_units\":{},\"clock_mhz\":{},\"global_mem\":{},\
+         \"local_mem\":{},\"constant_mem\":{},\"max_alloc\":{},\"max_work_group_size\":{},\
+         \"max_work_item_sizes\":[{}],\"image_support\":{},\"fp64\":{},\"extensions\":{}",
+        string(&device.name()),
+        string(&device.device_type().to_string()),
+        string(&device.vendor()),
+        string(&device.version()),
+        string(&device.driver_version()),
+        string(&device.opencl_c_version()),
+        device.compute_units(),
+        device.clock_mhz(),
+        device.global_mem(),
+        device.local_mem(),
+        device.constant_mem(),
+        device.max_alloc(),
+        device.max_work_group_size(),
+        join(&sizes, ","),
+        device.image_support(),
+        device.fp64(),
+        strings(&device.extensions()),
+    );
+    match check {
+        Some(Check::Built) => out.push_str(",\"check\":{\"built\":true}"),
+        Some(Check::Failed(log)) => {
+            let _ = write!(
+                out,
+                ",\"check\":{{\"built\":false,\"log\":{}}}",
+                string(log)
+            );
+        }
+        None => {}
+    }
+    out.push('}');
+    out
+}
+
+/// string() writes a JSON string, escaped.
+fn string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn strings(values: &[String]) -> String {
+    let values: Vec<String> = values.iter().map(|v| string(v)).collect();
+    format!("[{}]", values.join(","))
+}
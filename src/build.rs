@@ -0,0 +1,481 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// How bad a diagnostic from the OpenCL compiler is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+            Self::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A single message from the compiler, pointing at a place in the kernel source.
+/// Lines and columns are 1-based, like the compiler prints them. A column of 0 means the
+/// compiler didn't give one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Parse one line of a build log. Most drivers use the clang format
+    /// (`<source>:3:5: error: ...`), older NVIDIA drivers use `<kernel>(3): error: ...`.
+    /// Returns None for lines that aren't diagnostics, like the source snippets clang prints.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use obrah::build::{Diagnostic, Severity};
+    ///
+    /// let diag = Diagnostic::parse("<source>:3:5: error: use of undeclared identifier 'x'").unwrap();
+    /// assert_eq!(diag.file, "<source>");
+    /// assert_eq!((diag.line, diag.column), (3, 5));
+    /// assert_eq!(diag.severity, Severity::Error);
+    /// assert_eq!(diag.message, "use of undeclared identifier 'x'");
+    ///
+    /// let diag = Diagnostic::parse("<kernel>(12): warning: variable \"y\" is unused").unwrap();
+    /// assert_eq!((diag.line, diag.column), (12, 0));
+    /// assert_eq!(diag.severity, Severity::Warning);
+    ///
+    /// assert!(Diagnostic::parse("    x = 1;").is_none());
+    /// ```
+    pub fn parse(line: &str) -> Option<Diagnostic> {
+        let markers = [
+            (": fatal error:", Severity::Error),
+            (": error:", Severity::Error),
+            (": warning:", Severity::Warning),
+            (": note:", Severity::Note),
+        ];
+        let (at, marker, severity) = markers
+            .iter()
+            .filter_map(|(marker, severity)| line.find(marker).map(|at| (at, *marker, *severity)))
+            .min_by_key(|(at, _, _)| *at)?;
+
+        let (file, line_no, column) = parse_location(&line[..at])?;
+        Some(Diagnostic {
+            file,
+            line: line_no,
+            column,
+            severity,
+            message: line[at + marker.len()..].trim().to_string(),
+        })
+    }
+
+    /// True if the diagnostic points into the program's own source, rather than a header
+    /// it included. Drivers name the source `<source>` (clang), `input.cl` (Intel) or
+    /// `<kernel>` (NVIDIA).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use obrah::build::Diagnostic;
+    ///
+    /// assert!(Diagnostic::parse("<source>:3:5: error: oops").unwrap().in_source());
+    /// assert!(Diagnostic::parse("<kernel>(3): error: oops").unwrap().in_source());
+    /// assert!(!Diagnostic::parse("/inc/tile.h:3:5: error: oops").unwrap().in_source());
+    /// ```
+    pub fn in_source(&self) -> bool {
+        matches!(self.file.as_str(), "<source>" | "input.cl" | "<kernel>")
+    }
+}
+
+/// Split `file:line:col`, `file:line` or `file(line)` into its parts.
+fn parse_location(loc: &str) -> Option<(String, usize, usize)> {
+    let loc = loc.trim();
+    if let Some(open) = loc.rfind('(')
+        && loc.ends_with(')')
+        && let Ok(line) = loc[open + 1..loc.len() - 1].parse()
+    {
+        return Some((loc[..open].to_string(), line, 0));
+    }
+
+    let mut parts = loc.rsplitn(3, ':');
+    let last = parts.next()?.parse::<usize>().ok()?;
+    let middle = parts.next()?;
+    match (middle.parse::<usize>(), parts.next()) {
+        (Ok(line), Some(file)) => Some((file.to_string(), line, last)),
+        _ => {
+            // only a line number, so the middle part was the end of the file name
+            let file = loc[..loc.len() - last.to_string().len() - 1].to_string();
+            Some((file, last, 0))
+        }
+    }
+}
+
+/// Pull every diagnostic out of a raw build log.
+pub fn parse_log(log: &str) -> Vec<Diagnostic> {
+    log.lines().filter_map(Diagnostic::parse).collect()
+}
+
+/// The build log of one device.
+#[derive(Debug, Clone)]
+pub struct DeviceLog {
+    pub device: String,
+    pub log: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl DeviceLog {
+    pub fn new(device: String, log: String) -> Self {
+        let diagnostics = parse_log(&log);
+        DeviceLog {
+            device,
+            log,
+            diagnostics,
+        }
+    }
+}
+
+/// BuildLog holds the CL_PROGRAM_BUILD_LOG of every device a program was built for,
+/// along with the source and options it was built with, so it can point at the broken line.
+/// It's returned inside ClError::BuildFailed when a build fails, and kept on the Program
+/// after a successful build so the warnings are still there.
+///
+/// Printing it gives a rustc-style report.
+///
+/// # Examples
+///
+/// ```rust
+/// use obrah::build::{BuildLog, DeviceLog};
+///
+/// let log = BuildLog {
+///     source: "__kernel void k() {\n    x = 1;\n}\n".to_string(),
+///     options: String::new(),
+///     devices: vec![DeviceLog::new(
+///         "gfx1030".to_string(),
+///         "<source>:2:5: error: use of undeclared identifier 'x'\n    x = 1;\n    ^\n".to_string(),
+///     )],
+/// };
+/// assert_eq!(log.errors().count(), 1);
+/// assert_eq!(
+///     log.to_string(),
+///     "build log for device `gfx1030`:\n\
+///      error: use of undeclared identifier 'x'\n\
+///      \x20--> <source>:2:5\n\
+///      \x20 |\n\
+///      2 |     x = 1;\n\
+///      \x20 |     ^\n\n"
+/// );
+///
+/// // errors in an included header only get their location, as the header isn't in source
+/// let log = BuildLog {
+///     devices: vec![DeviceLog::new(
+///         "gfx1030".to_string(),
+///         "/inc/tile.h:3:5: error: expected ';'\n".to_string(),
+///     )],
+///     ..log
+/// };
+/// assert_eq!(
+///     log.to_string(),
+///     "build log for device `gfx1030`:\n\
+///      error: expected ';'\n\
+///      \x20--> /inc/tile.h:3:5\n\n"
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct BuildLog {
+    pub source: String,
+    /// The options string the program was built with.
+    pub options: String,
+    pub devices: Vec<DeviceLog>,
+}
+
+impl BuildLog {
+    /// All diagnostics, from every device.
+    pub fn diagnostics(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.devices.iter().flat_map(|d| d.diagnostics.iter())
+    }
+
+    /// Only the errors.
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics().filter(|d| d.severity == Severity::Error)
+    }
+
+    /// Only the warnings.
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics()
+            .filter(|d| d.severity == Severity::Warning)
+    }
+
+    /// True if no device printed anything.
+    pub fn is_empty(&self) -> bool {
+        self.devices.iter().all(|d| d.log.trim().is_empty())
+    }
+}
+
+impl fmt::Display for BuildLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.options.is_empty() {
+            writeln!(f, "build options: {}", self.options)?;
+        }
+        for device in &self.devices {
+            if device.log.trim().is_empty() {
+                continue;
+            }
+            writeln!(f, "build log for device `{}`:", device.device)?;
+            if device.diagnostics.is_empty() {
+                // the driver uses a format we don't know, so just show it as is
+                writeln!(f, "{}", device.log.trim_end())?;
+                continue;
+            }
+            for diag in &device.diagnostics {
+                render(f, diag, &self.source)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write one diagnostic the way rustc does, with the source line and a caret under the column.
+/// Diagnostics in included headers only get the location, as their lines aren't in source.
+fn render(f: &mut fmt::Formatter<'_>, diag: &Diagnostic, source: &str) -> fmt::Result {
+    writeln!(f, "{}: {}", diag.severity, diag.message)?;
+    let width = diag.line.to_string().len();
+    let pad = " ".repeat(width);
+    if diag.column > 0 {
+        writeln!(f, "{pad}--> {}:{}:{}", diag.file, diag.line, diag.column)?;
+    } else {
+        writeln!(f, "{pad}--> {}:{}", diag.file, diag.line)?;
+    }
+
+    let code = diag
+        .line
+        .checked_sub(1)
+        .filter(|_| diag.in_source())
+        .and_then(|i| source.lines().nth(i));
+    let Some(code) = code else {
+        return writeln!(f);
+    };
+    writeln!(f, "{pad} |")?;
+    writeln!(f, "{} | {}", diag.line, code)?;
+    if diag.column > 0 {
+        // keep tabs so the caret lines up with the code above it
+        let indent: String = code
+            .chars()
+            .take(diag.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "{pad} | {indent}^")?;
+    } else {
+        writeln!(f, "{pad} |")?;
+    }
+    writeln!(f)
+}
+
+/// The OpenCL C version to compile for, passed as -cl-std.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClStd {
+    Cl1_1,
+    Cl1_2,
+    Cl2_0,
+    Cl3_0,
+}
+
+impl fmt::Display for ClStd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cl1_1 => write!(f, "CL1.1"),
+            Self::Cl1_2 => write!(f, "CL1.2"),
+            Self::Cl2_0 => write!(f, "CL2.0"),
+            Self::Cl3_0 => write!(f, "CL3.0"),
+        }
+    }
+}
+
+/// BuildOptions is a builder for the options string handed to the OpenCL compiler.
+/// Pass it to Env::program() or Program::new(). The default has no options at all.
+///
+/// # Examples
+///
+/// ```rust
+/// use obrah::build::{BuildOptions, ClStd};
+///
+/// let options = BuildOptions::new()
+///     .define("TILE", 16)
+///     .define_flag("USE_SHADOWS")
+///     .include_dir("kernels/include")
+///     .fast_relaxed_math()
+///     .std(ClStd::Cl2_0)
+///     .warnings_as_errors();
+///
+/// assert_eq!(
+///     options.to_string(),
+///     "-D TILE=16 -D USE_SHADOWS -I kernels/include -cl-fast-relaxed-math -cl-std=CL2.0 -Werror"
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    defines: Vec<(String, Option<String>)>,
+    include_dirs: Vec<PathBuf>,
+    // flags that are also allowed when linking
+    math: Vec<&'static str>,
+    // flags that only mean something when compiling
+    optimizations: Vec<&'static str>,
+    std: Option<ClStd>,
+    warnings_as_errors: bool,
+    disable_warnings: bool,
+    extra: Vec<String>,
+}
+
+impl BuildOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a preprocessor define with a value, like `-D TILE=16`.
+    pub fn define(mut self, name: &str, value: impl fmt::Display) -> Self {
+        self.defines
+            .push((name.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Add a preprocessor define without a value, like `-D USE_SHADOWS`.
+    pub fn define_flag(mut self, name: &str) -> Self {
+        self.defines.push((name.to_string(), None));
+        self
+    }
+
+    /// Add a directory to search for #include files in. Programs built with one aren't
+    /// cached, as a header could change without the source changing.
+    pub fn include_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// -cl-fast-relaxed-math. Turns on every other relaxed math flag too.
+    pub fn fast_relaxed_math(self) -> Self {
+        self.math("-cl-fast-relaxed-math")
+    }
+
+    /// -cl-unsafe-math-optimizations.
+    pub fn unsafe_math_optimizations(self) -> Self {
+        self.math("-cl-unsafe-math-optimizations")
+    }
+
+    /// -cl-finite-math-only. Assumes there are no NaNs or infinities.
+    pub fn finite_math_only(self) -> Self {
+        self.math("-cl-finite-math-only")
+    }
+
+    /// -cl-no-signed-zeros.
+    pub fn no_signed_zeros(self) -> Self {
+        self.math("-cl-no-signed-zeros")
+    }
+
+    /// -cl-denorms-are-zero.
+    pub fn denorms_are_zero(self) -> Self {
+        self.math("-cl-denorms-are-zero")
+    }
+
+    /// -cl-mad-enable. Lets a * b + c be done with less precision.
+    pub fn mad_enable(self) -> Self {
+        self.optimization("-cl-mad-enable")
+    }
+
+    /// -cl-single-precision-constant. Treats double constants as floats.
+    pub fn single_precision_constant(self) -> Self {
+        self.optimization("-cl-single-precision-constant")
+    }
+
+    /// -cl-opt-disable. Turns off all optimizations, handy for debugging.
+    pub fn opt_disable(self) -> Self {
+        self.optimization("-cl-opt-disable")
+    }
+
+    /// The OpenCL C version to compile for.
+    pub fn std(mut self, std: ClStd) -> Self {
+        self.std = Some(std);
+        self
+    }
+
+    /// -Werror. Makes every warning fail the build.
+    pub fn warnings_as_errors(mut self) -> Self {
+        self.warnings_as_errors = true;
+        self
+    }
+
+    /// -w. Stops the compiler from printing warnings.
+    pub fn disable_warnings(mut self) -> Self {
+        self.disable_warnings = true;
+        self
+    }
+
+    /// Add a raw option that has no method of its own, like a vendor specific one.
+    pub fn option(mut self, option: &str) -> Self {
+        self.extra.push(option.to_string());
+        self
+    }
+
+    /// True if the build can #include headers from directories, whose contents the cache
+    /// can't see.
+    pub(crate) fn has_include_dirs(&self) -> bool {
+        !self.include_dirs.is_empty() || self.extra.iter().any(|o| o.starts_with("-I"))
+    }
+
+    /// The options allowed for clLinkProgram. Defines, include paths and the like only
+    /// mean something to the compiler, and some drivers reject them when linking.
+    pub(crate) fn link_options(&self) -> String {
+        self.math.join(" ")
+    }
+
+    fn math(mut self, flag: &'static str) -> Self {
+        if !self.math.contains(&flag) {
+            self.math.push(flag);
+        }
+        self
+    }
+
+    fn optimization(mut self, flag: &'static str) -> Self {
+        if !self.optimizations.contains(&flag) {
+            self.optimizations.push(flag);
+        }
+        self
+    }
+}
+
+impl fmt::Display for BuildOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = Vec::new();
+        for (name, value) in &self.defines {
+            match value {
+                Some(value) => options.push(format!("-D {name}={value}")),
+                None => options.push(format!("-D {name}")),
+            }
+        }
+        for dir in &self.include_dirs {
+            let dir = dir.display().to_string();
+            if dir.contains(char::is_whitespace) {
+                options.push(format!("-I \"{dir}\""));
+            } else {
+                options.push(format!("-I {dir}"));
+            }
+        }
+        options.extend(self.math.iter().map(|s| s.to_string()));
+        options.extend(self.optimizations.iter().map(|s| s.to_string()));
+        if let Some(std) = self.std {
+            options.push(format!("-cl-std={std}"));
+        }
+        if self.warnings_as_errors {
+            options.push("-Werror".to_string());
+        }
+        if self.disable_warnings {
+            options.push("-w".to_string());
+        }
+        options.extend(self.extra.iter().cloned());
+        write!(f, "{}", options.join(" "))
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// numbers each store(), so threads of one process don't write to the same temporary file
+static STORES: AtomicUsize = AtomicUsize::new(0);
+
+/// KernelCache keeps built program binaries on disk, so the next run can load them
+/// instead of compiling from source again. Turn it on with Env::use_cache().
+///
+/// Binaries are stored under a key made from the source, the build options, the device
+/// name and the driver version, so changing any of them builds from source again.
+/// If the driver rejects a cached binary, the program is built from source and the
+/// cache entry is replaced. Programs built with include directories (BuildOptions::include_dir()
+/// or a raw -I option) aren't cached at all, since the key can't tell when a header changed.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let program = env
+///         .use_cache("target/kernel-cache")
+///         .use_kernel("examples/raytrace_kernel.cl")?
+///         .program(&BuildOptions::new())?; // only slow the first time
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KernelCache {
+    pub dir: PathBuf,
+}
+
+impl KernelCache {
+    /// Make a cache that lives in a directory. The directory is created when the
+    /// first binary is stored.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        KernelCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The key a binary is stored under. It's a hash of everything that changes what the
+    /// driver would build, and it stays the same between runs and Rust versions.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use obrah::cache::KernelCache;
+    ///
+    /// let key = KernelCache::key("__kernel void k() {}", "", "gfx1030", "3614.0");
+    /// assert_eq!(key, KernelCache::key("__kernel void k() {}", "", "gfx1030", "3614.0"));
+    /// assert_ne!(key, KernelCache::key("__kernel void k() {}", "-D A", "gfx1030", "3614.0"));
+    /// assert_ne!(key, KernelCache::key("__kernel void k() {}", "", "gfx1030", "3615.0"));
+    /// ```
+    pub fn key(source: &str, options: &str, device: &str, driver: &str) -> String {
+        // FNV-1a, as std's hasher isn't guaranteed to give the same result between releases
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for part in [source, options, device, driver] {
+            for byte in part.bytes().chain(std::iter::once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        format!("{hash:016x}")
+    }
+
+    /// Load the binary stored under a key, if there is one.
+    pub fn load(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(key)).ok().filter(|b| !b.is_empty())
+    }
+
+    /// Store a binary under a key. It's written to a temporary file first, so another
+    /// process never sees half a binary.
+    pub fn store(&self, key: &str, binary: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let nonce = STORES.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .dir
+            .join(format!("{key}.{}.{nonce}.tmp", std::process::id()));
+        let result = fs::write(&tmp, binary).and_then(|_| fs::rename(&tmp, self.path(key)));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Remove a single entry.
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove every cached binary.
+    pub fn clear(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "bin") {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.bin"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_load_clear() {
+        let dir = std::env::temp_dir().join(format!("obrah-cache-test-{}", std::process::id()));
+        let cache = KernelCache::new(&dir);
+        let key = KernelCache::key("__kernel void k() {}", "", "test", "1.0");
+
+        assert_eq!(cache.load(&key), None);
+        cache.store(&key, b"binary").unwrap();
+        assert_eq!(cache.load(&key).as_deref(), Some(&b"binary"[..]));
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.load(&key), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
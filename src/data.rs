@@ -1,5 +1,10 @@
-use crate::runtime::Env;
+use crate::error::check;
+use crate::event::{Event, Pending, WaitList};
+use crate::profile::{Command, Direction};
+use crate::runtime::{ClError, Env};
 use obwio::*;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 
 /// The padded float3 type. Necessary when making buffers of float3, as normal Rust
 /// [f32; 3] is not padded to 16 bytes.
@@ -32,47 +37,567 @@ impl Float2 {
     }
 }
 
+/// A 2-D block of elements, for Buffer::copy_rect(). x and y are where it starts, counted in
+/// elements along a row and in rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
 /// Buffer is a struct that holds, well, a buffer.
 /// It is returned by Buffer::new().
 ///
+/// A Buffer is only the memory on the device and how many elements fit in it; it doesn't keep
+/// a copy of the data on the host. Uploads and downloads borrow the host's slice for as long
+/// as they take. If a copy on the host is wanted anyway, make the buffer with with_mirror(),
+/// and move data between the two with push() and pull().
+///
+/// Whole-buffer transfers need a slice exactly len() long, and partial ones with to_range()
+/// and from_range() have to fit in the buffer; otherwise they give back an error instead of
+/// touching memory they shouldn't.
+///
 /// # Examples
 ///
-/// ```rust
+/// ```rust,no_run
 /// use obrah::data::Buffer;
 /// use obrah::runtime::Env;
 ///
-/// let mut env = Env::new(0, 0);
-/// let mut data = vec![1.0f32; 10];
-/// let buf = Buffer::new(&mut env, &mut data);
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let data = vec![1.0f32; 10];
+///     let mut buf = Buffer::new(&mut env, &data)?;
+///     buf.to(&data, &mut env)?;
+///
+///     // or keep the data with the buffer
+///     let mut mirrored = Buffer::with_mirror(&mut env, vec![1.0f32; 10])?;
+///     mirrored.mirror_mut().unwrap()[0] = 2.0;
+///     mirrored.push(&mut env)?;
+///     Ok(())
+/// }
 /// ```
-
 pub struct Buffer<T>
 where
     T: Clone + 'static,
 {
     pub buffer: cl_mem,
-    pub data: Vec<T>,
+    len: usize,
+    mirror: Option<Vec<T>>,
+    // the flags it was made with, minus the host pointer ones, for resize()
+    flags: cl_mem_flags,
 }
 
 impl<T> Buffer<T>
 where
     T: Clone + 'static,
 {
-    pub fn to(&mut self, env: &mut Env) {
-        let mut data = self.data.clone();
-        to_gpu(env, &mut data, self);
+    /// Send data to the buffer. data has to be as long as the buffer.
+    pub fn to(&mut self, data: &[T], env: &mut Env) -> Result<(), ClError> {
+        self.enqueue_write(data, env, &[])?;
+        Ok(())
+    }
+    /// Get the buffer's contents into data. data has to be as long as the buffer.
+    pub fn from(&mut self, data: &mut [T], env: &mut Env) -> Result<(), ClError>
+    where
+        T: Copy + 'static,
+    {
+        self.enqueue_read(data, env, &[])?;
+        Ok(())
+    }
+    /// Send data to part of the buffer, starting offset elements in.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use obrah::data::Buffer;
+    /// use obrah::runtime::{ClError, Env};
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let mut env = Env::new(0, 0)?;
+    ///     let mut buf = Buffer::<f32>::with_len(&mut env, 1920 * 1080)?;
+    ///     let row = vec![1.0f32; 1920];
+    ///     buf.to_range(1920 * 540, &row, &mut env)?; // the middle row
+    ///
+    ///     let mut too_long = vec![0.0f32; 1920 * 1080 + 1];
+    ///     let err = buf.from(&mut too_long, &mut env).unwrap_err();
+    ///     assert!(matches!(err, ClError::LengthMismatch { .. }));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn to_range(&mut self, offset: usize, data: &[T], env: &mut Env) -> Result<(), ClError> {
+        self.check_range(offset, data.len())?;
+        to_gpu(env, data, self, offset, &[], CL_TRUE)?;
+        Ok(())
+    }
+    /// Map the buffer into host memory to read it, without copying it where the device shares
+    /// memory with the host (integrated GPUs, CPUs). The map borrows env, so nothing can be
+    /// launched or transferred until it is dropped, which unmaps it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use obrah::data::BufferBuilder;
+    /// use obrah::runtime::Env;
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let mut env = Env::new(0, 0)?;
+    ///     let mut buf = BufferBuilder::<f32>::new()
+    ///         .len(1024)
+    ///         .alloc_host()
+    ///         .build(&mut env)?;
+    ///
+    ///     let mut map = buf.map_write(&env)?;
+    ///     map.fill(1.0);
+    ///     drop(map); // unmapped, env can be used again
+    ///
+    ///     // run kernels on buf...
+    ///
+    ///     let map = buf.map_read(&env)?;
+    ///     println!("sum: {}", map.iter().sum::<f32>());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn map_read<'a>(&'a self, env: &'a Env) -> Result<MapRead<'a, T>, ClError>
+    where
+        T: Copy + 'static,
+    {
+        let map = Mapping::new(env, self, CL_MAP_READ.into())?;
+        Ok(MapRead {
+            map,
+            _borrow: PhantomData,
+        })
+    }
+    /// Map the buffer to write it. What it held before is still there.
+    pub fn map_write<'a>(&'a mut self, env: &'a Env) -> Result<MapWrite<'a, T>, ClError>
+    where
+        T: Copy + 'static,
+    {
+        let map = Mapping::new(env, self, CL_MAP_WRITE.into())?;
+        Ok(MapWrite {
+            map,
+            _borrow: PhantomData,
+        })
+    }
+    /// Map the buffer to read and write it.
+    pub fn map_read_write<'a>(&'a mut self, env: &'a Env) -> Result<MapWrite<'a, T>, ClError>
+    where
+        T: Copy + 'static,
+    {
+        let flags = cl_map_flags::from(CL_MAP_READ) | cl_map_flags::from(CL_MAP_WRITE);
+        let map = Mapping::new(env, self, flags)?;
+        Ok(MapWrite {
+            map,
+            _borrow: PhantomData,
+        })
+    }
+    /// Get part of the buffer, starting offset elements in, into data.
+    pub fn from_range(
+        &mut self,
+        offset: usize,
+        data: &mut [T],
+        env: &mut Env,
+    ) -> Result<(), ClError>
+    where
+        T: Copy + 'static,
+    {
+        self.check_range(offset, data.len())?;
+        from_gpu(env, data, self, offset, &[], CL_TRUE)?;
+        Ok(())
+    }
+    /// Like to(), but the write waits for the events in after first.
+    /// It still blocks until data is copied, so the returned Event is already complete;
+    /// for a chain that doesn't block, use to_async() and Kernel::enqueue().
+    pub fn enqueue_write(
+        &mut self,
+        data: &[T],
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Event, ClError> {
+        self.check_len(data.len())?;
+        to_gpu(env, data, self, 0, after, CL_TRUE)
+    }
+    /// Like from(), but the read waits for the events in after first, e.g. the kernel that
+    /// fills the buffer. It blocks until data is filled, so the returned Event is already
+    /// complete; to read without blocking, use from_async().
+    pub fn enqueue_read(
+        &mut self,
+        data: &mut [T],
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Event, ClError>
+    where
+        T: Copy + 'static,
+    {
+        self.check_len(data.len())?;
+        from_gpu(env, data, self, 0, after, CL_TRUE)
+    }
+    /// Like enqueue_write(), but it gives back before the data is copied, so it can be chained
+    /// with kernels without blocking.
+    /// data is moved into the Pending until the write is done, so it can't change under it;
+    /// waiting on the Pending gives it back. See Pending.
+    pub fn to_async(
+        &self,
+        data: Vec<T>,
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Pending<T>, ClError> {
+        self.check_len(data.len())?;
+        let event = to_gpu(env, &data, self, 0, after, CL_FALSE)?;
+        Ok(Pending::new(event, data))
+    }
+    /// Like enqueue_read(), but it gives back before the data arrives.
+    /// data is moved into the Pending until the read is done, so it can't be looked at
+    /// half-filled; waiting on the Pending gives it back filled. See Pending.
+    pub fn from_async(
+        &self,
+        mut data: Vec<T>,
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Pending<T>, ClError>
+    where
+        T: Copy + 'static,
+    {
+        self.check_len(data.len())?;
+        let event = from_gpu(env, &mut data, self, 0, after, CL_FALSE)?;
+        Ok(Pending::new(event, data))
+    }
+    /// Move the buffer's contents to the index'th device of a multi-device Env, ahead of a
+    /// kernel that uses it there. Launches on other devices would move it anyway, this just
+    /// does it early, and can overlap with other work. See Env::from_devices().
+    pub fn migrate(
+        &self,
+        env: &mut Env,
+        device: usize,
+        after: &[&Event],
+    ) -> Result<Event, ClError> {
+        let queue = *env.queues.get(device).ok_or(ClError::NonexistentDevice {
+            index: device,
+            count: env.queues.len(),
+        })?;
+        let after = WaitList::new(after);
+        unsafe {
+            let mut event = std::ptr::null_mut();
+            let err = clEnqueueMigrateMemObjects(
+                queue,
+                1,
+                &self.buffer,
+                0,
+                after.len(),
+                after.as_ptr(),
+                &mut event,
+            );
+            check("clEnqueueMigrateMemObjects", err)?;
+            check("clFlush", clFlush(queue))?;
+            Ok(Event::new(event))
+        }
+    }
+    /// Copy all of src into the buffer, on the device. src has to be as long as the buffer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use obrah::data::{Buffer, Rect};
+    /// use obrah::runtime::Env;
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let mut env = Env::new(0, 0)?;
+    ///     let mut frame = Buffer::<f32>::with_len(&mut env, 1920 * 1080)?;
+    ///     let mut accum = Buffer::<f32>::with_len(&mut env, 1920 * 1080)?;
+    ///     accum.fill(0.0, &mut env)?; // clear it before the first pass
+    ///
+    ///     // run kernels...
+    ///
+    ///     frame.copy_from(&accum, &mut env)?;
+    ///     // put a 256x256 tile of frame in the top left corner of a 4K image
+    ///     let mut image = Buffer::<f32>::with_len(&mut env, 3840 * 2160)?;
+    ///     let tile = Rect::new(832, 412, 256, 256);
+    ///     image.copy_rect(3840, (0, 0), (&frame, 1920), tile, &mut env)?;
+    ///     // and make frame bigger, keeping what it has
+    ///     frame.resize(3840 * 2160, &mut env)?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn copy_from(&mut self, src: &Buffer<T>, env: &mut Env) -> Result<(), ClError> {
+        self.enqueue_copy(src, env, &[])?.wait()
+    }
+    /// Copy len elements of src, starting src_offset in, to the buffer offset elements in.
+    /// Copying nothing does nothing.
+    pub fn copy_range(
+        &mut self,
+        offset: usize,
+        src: &Buffer<T>,
+        src_offset: usize,
+        len: usize,
+        env: &mut Env,
+    ) -> Result<(), ClError> {
+        src.check_range(src_offset, len)?;
+        self.check_range(offset, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        copy_gpu(env, src, src_offset, self, offset, len, &[])?.wait()
+    }
+    /// Copy all of src into the buffer, without waiting for it. Runs after the events in after.
+    pub fn enqueue_copy(
+        &mut self,
+        src: &Buffer<T>,
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Event, ClError> {
+        self.check_len(src.len)?;
+        copy_gpu(env, src, 0, self, 0, self.len, after)
+    }
+    /// Copy the rect block of src into the buffer, with its top left corner at (x, y) = at.
+    /// src is the buffer and how many elements its rows have, and row_len is the same for this
+    /// buffer, so the two can be images of different widths. Copying nothing does nothing.
+    pub fn copy_rect(
+        &mut self,
+        row_len: usize,
+        at: (usize, usize),
+        src: (&Buffer<T>, usize),
+        rect: Rect,
+        env: &mut Env,
+    ) -> Result<(), ClError> {
+        self.enqueue_copy_rect(row_len, at, src, rect, env, &[])?
+            .wait()
+    }
+    /// Like copy_rect(), but without waiting for it. Runs after the events in after.
+    pub fn enqueue_copy_rect(
+        &mut self,
+        row_len: usize,
+        at: (usize, usize),
+        src: (&Buffer<T>, usize),
+        rect: Rect,
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Event, ClError> {
+        let (src, src_row_len) = src;
+        let after = WaitList::new(after);
+        if rect.width == 0 || rect.height == 0 {
+            // nothing to copy, but the event still has to wait for after
+            return marker(env, &after);
+        }
+        src.check_rect(src_row_len, rect)?;
+        self.check_rect(row_len, Rect::new(at.0, at.1, rect.width, rect.height))?;
+        let size = std::mem::size_of::<T>();
+        let src_origin = [rect.x * size, rect.y, 0];
+        let dst_origin = [at.0 * size, at.1, 0];
+        let region = [rect.width * size, rect.height, 1];
+        unsafe {
+            let mut event = std::ptr::null_mut();
+            let err = clEnqueueCopyBufferRect(
+                env.queue,
+                src.buffer,
+                self.buffer,
+                src_origin.as_ptr(),
+                dst_origin.as_ptr(),
+                region.as_ptr(),
+                src_row_len * size,
+                0,
+                row_len * size,
+                0,
+                after.len(),
+                after.as_ptr(),
+                &mut event,
+            );
+            let bytes = rect.width * rect.height * size;
+            check("clEnqueueCopyBufferRect", err).map_err(|e| e.with_size(bytes))?;
+            check("clFlush", clFlush(env.queue))?;
+            let event = Event::new(event);
+            env.record(Command::Transfer(Direction::Copy, bytes), &event);
+            Ok(event)
+        }
     }
-    pub fn from(&mut self, data: &mut [T], env: &mut Env)
+    /// Set every element of the buffer to value, on the device. T has to be 1, 2, 4, 8, 16,
+    /// 32, 64 or 128 bytes; Float3 is, [f32; 3] isn't, and gives back FillPatternSize.
+    pub fn fill(&mut self, value: T, env: &mut Env) -> Result<(), ClError>
     where
         T: Copy + 'static,
     {
-        from_gpu(env, data, self);
+        self.enqueue_fill(value, env, &[])?.wait()
     }
-    pub fn new(env: &mut Env, data: &[T]) -> Buffer<T>
+    /// Set len elements, starting offset in, to value. Filling nothing does nothing.
+    pub fn fill_range(
+        &mut self,
+        value: T,
+        offset: usize,
+        len: usize,
+        env: &mut Env,
+    ) -> Result<(), ClError>
+    where
+        T: Copy + 'static,
+    {
+        self.check_range(offset, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        fill_gpu(env, self, value, offset, len, &[])?.wait()
+    }
+    /// Set every element to value, without waiting for it. Runs after the events in after.
+    pub fn enqueue_fill(
+        &mut self,
+        value: T,
+        env: &mut Env,
+        after: &[&Event],
+    ) -> Result<Event, ClError>
+    where
+        T: Copy + 'static,
+    {
+        fill_gpu(env, self, value, 0, self.len, after)
+    }
+    /// Make the buffer len elements long, keeping its contents up to the shorter of the two
+    /// lengths. A new buffer with the same flags is made and the contents copied on the
+    /// device, so kernel args set to the old one have to be set again. Buffers with a mirror
+    /// can't be resized, as the mirror would stop fitting; take_mirror() first.
+    pub fn resize(&mut self, len: usize, env: &mut Env) -> Result<(), ClError> {
+        if self.mirror.is_some() {
+            return Err(ClError::MirroredResize);
+        }
+        let mut resized = buffer_write::<T>(env, self.flags, len, std::ptr::null_mut())?;
+        let keep = self.len.min(len);
+        if keep > 0 {
+            copy_gpu(env, self, 0, &resized, 0, keep, &[])?.wait()?;
+        }
+        // the old buffer goes out with resized
+        std::mem::swap(self, &mut resized);
+        Ok(())
+    }
+    /// Make a buffer big enough for data. Nothing is sent yet; use to() for that.
+    /// To make it and fill it in one go, or to pick memory flags, use BufferBuilder.
+    pub fn new(env: &mut Env, data: &[T]) -> Result<Buffer<T>, ClError>
     where
         T: Clone + 'static,
     {
-        buffer_write(env, data)
+        buffer_write(
+            env,
+            CL_MEM_READ_WRITE.into(),
+            data.len(),
+            std::ptr::null_mut(),
+        )
+    }
+    /// Make a buffer of len elements. Its contents are whatever the device had there.
+    pub fn with_len(env: &mut Env, len: usize) -> Result<Buffer<T>, ClError> {
+        buffer_write(env, CL_MEM_READ_WRITE.into(), len, std::ptr::null_mut())
+    }
+    /// Make a buffer that keeps data on the host as its mirror, and send data to it.
+    /// The Vec is moved in, not copied.
+    pub fn with_mirror(env: &mut Env, data: Vec<T>) -> Result<Buffer<T>, ClError> {
+        let mut buf = Buffer::with_len(env, data.len())?;
+        to_gpu(env, &data, &buf, 0, &[], CL_TRUE)?;
+        buf.mirror = Some(data);
+        Ok(buf)
+    }
+    /// The number of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The host mirror, if the buffer was made with with_mirror().
+    pub fn mirror(&self) -> Option<&[T]> {
+        self.mirror.as_deref()
+    }
+    pub fn mirror_mut(&mut self) -> Option<&mut [T]> {
+        self.mirror.as_deref_mut()
+    }
+    /// Take the host mirror out of the buffer, so it's just device memory from now on.
+    pub fn take_mirror(&mut self) -> Option<Vec<T>> {
+        self.mirror.take()
+    }
+    /// Send the host mirror to the buffer. Does nothing without a mirror.
+    pub fn push(&mut self, env: &mut Env) -> Result<(), ClError> {
+        match &self.mirror {
+            Some(mirror) => to_gpu(env, mirror, self, 0, &[], CL_TRUE).map(drop),
+            None => Ok(()),
+        }
+    }
+    /// Get the buffer's contents into the host mirror. Does nothing without a mirror.
+    pub fn pull(&mut self, env: &mut Env) -> Result<(), ClError>
+    where
+        T: Copy + 'static,
+    {
+        let Some(mut mirror) = self.mirror.take() else {
+            return Ok(());
+        };
+        let result = from_gpu(env, &mut mirror, self, 0, &[], CL_TRUE);
+        self.mirror = Some(mirror);
+        result.map(drop)
+    }
+    fn check_len(&self, len: usize) -> Result<(), ClError> {
+        check_len(self.len, len)
+    }
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), ClError> {
+        check_range(self.len, offset, len)
+    }
+    fn check_rect(&self, row_len: usize, rect: Rect) -> Result<(), ClError> {
+        check_rect(self.len, row_len, rect)
+    }
+}
+
+// check_len() makes sure a whole-buffer transfer's slice is as long as a buffer of
+// buffer_len elements.
+fn check_len(buffer_len: usize, len: usize) -> Result<(), ClError> {
+    if len != buffer_len {
+        return Err(ClError::LengthMismatch {
+            expected: buffer_len,
+            found: len,
+        });
+    }
+    Ok(())
+}
+
+// check_range() makes sure len elements, offset elements in, fit in a buffer of buffer_len
+// elements, like Buffer's range transfers, copies and fills need.
+fn check_range(buffer_len: usize, offset: usize, len: usize) -> Result<(), ClError> {
+    if offset.checked_add(len).is_none_or(|end| end > buffer_len) {
+        return Err(ClError::OutOfBounds {
+            offset,
+            len,
+            buffer_len,
+        });
+    }
+    Ok(())
+}
+
+// check_rect() makes sure a 2-D block fits in a buffer of buffer_len elements, read as rows
+// of row_len, like Buffer::copy_rect() needs. An empty block always fits.
+fn check_rect(buffer_len: usize, row_len: usize, rect: Rect) -> Result<(), ClError> {
+    if rect.width == 0 || rect.height == 0 {
+        return Ok(());
+    }
+    // where the block starts, and how far its last row ends past that
+    let offset = rect
+        .y
+        .checked_mul(row_len)
+        .and_then(|o| o.checked_add(rect.x));
+    let len = (rect.height - 1)
+        .checked_mul(row_len)
+        .and_then(|l| l.checked_add(rect.width));
+    let in_row = rect
+        .x
+        .checked_add(rect.width)
+        .is_some_and(|end| end <= row_len);
+    match (offset, len) {
+        (Some(offset), Some(len)) if in_row => check_range(buffer_len, offset, len),
+        (offset, len) => Err(ClError::OutOfBounds {
+            offset: offset.unwrap_or(usize::MAX),
+            len: len.unwrap_or(usize::MAX),
+            buffer_len,
+        }),
     }
 }
 
@@ -85,80 +610,537 @@ where
     }
 }
 
-/// Create a buffer in order to be sent to the GPU. This function is used implicitly by Buffer::new().
-/// You don't have to call it.
-fn buffer_write<T>(env: &mut Env, data: &[T]) -> Buffer<T>
+/// MapRead is a buffer mapped for reading, from Buffer::map_read(). It derefs to the
+/// buffer's contents and unmaps when dropped.
+#[must_use = "the buffer is unmapped as soon as the map is dropped"]
+pub struct MapRead<'a, T> {
+    map: Mapping,
+    _borrow: PhantomData<(&'a [T], &'a Env)>,
+}
+
+/// MapWrite is a buffer mapped for writing, from Buffer::map_write() or
+/// Buffer::map_read_write(). It derefs to the buffer's contents and unmaps when dropped.
+#[must_use = "the buffer is unmapped as soon as the map is dropped"]
+pub struct MapWrite<'a, T> {
+    map: Mapping,
+    _borrow: PhantomData<(&'a mut [T], &'a Env)>,
+}
+
+impl<T> MapRead<'_, T> {
+    /// Unmap the buffer now, and see if that worked. Dropping the map does the same but
+    /// ignores errors.
+    pub fn unmap(mut self) -> Result<(), ClError> {
+        self.map.unmap()
+    }
+}
+
+impl<T> MapWrite<'_, T> {
+    /// Unmap the buffer now, and see if that worked. The writes are on the device after.
+    pub fn unmap(mut self) -> Result<(), ClError> {
+        self.map.unmap()
+    }
+}
+
+impl<T> Deref for MapRead<'_, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { self.map.slice() }
+    }
+}
+
+impl<T> Deref for MapWrite<'_, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { self.map.slice() }
+    }
+}
+
+impl<T> DerefMut for MapWrite<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { self.map.slice_mut() }
+    }
+}
+
+/// The mapped memory itself, shared by MapRead and MapWrite. ptr is null once unmapped.
+struct Mapping {
+    buffer: cl_mem,
+    queue: cl_command_queue,
+    ptr: *mut std::ffi::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    /// Map all of buffer on the queue in use, and wait until it's mapped.
+    fn new<T: Clone + 'static>(
+        env: &Env,
+        buffer: &Buffer<T>,
+        flags: cl_map_flags,
+    ) -> Result<Mapping, ClError> {
+        let size = buffer.len * std::mem::size_of::<T>();
+        unsafe {
+            let mut err = 0;
+            let ptr = clEnqueueMapBuffer(
+                env.queue,
+                buffer.buffer,
+                CL_TRUE,
+                flags,
+                0,
+                size,
+                0,
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                &mut err,
+            );
+            check("clEnqueueMapBuffer", err).map_err(|e| e.with_size(size))?;
+            Ok(Mapping {
+                buffer: buffer.buffer,
+                queue: env.queue,
+                ptr,
+                len: buffer.len,
+            })
+        }
+    }
+
+    /// The mapped memory. Only valid until unmap().
+    unsafe fn slice<T>(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const T, self.len) }
+    }
+
+    /// The mapped memory, to write. Only valid until unmap().
+    unsafe fn slice_mut<T>(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut T, self.len) }
+    }
+
+    /// Unmap, and wait for it so the next command on any queue sees the writes.
+    fn unmap(&mut self) -> Result<(), ClError> {
+        if self.ptr.is_null() {
+            return Ok(());
+        }
+        let ptr = std::mem::replace(&mut self.ptr, std::ptr::null_mut());
+        unsafe {
+            let mut event = std::ptr::null_mut();
+            let err = clEnqueueUnmapMemObject(
+                self.queue,
+                self.buffer,
+                ptr,
+                0,
+                std::ptr::null(),
+                &mut event,
+            );
+            check("clEnqueueUnmapMemObject", err)?;
+            Event::new(event).wait()
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = self.unmap();
+    }
+}
+
+/// BufferBuilder makes a buffer with the memory flags picked, instead of the read-write
+/// buffer Buffer::new() gives. The flags only tell the driver how the buffer is going to be
+/// used, so it can place it well; a kernel writing to a read_only() buffer is undefined.
+///
+/// copy_from() fills the buffer when it's made, in the same call, and use_host() hands the
+/// driver a Vec to use as the buffer's memory, which the buffer keeps until it is dropped.
+/// Either one sets the length to the data's; otherwise len() does. Giving both, with lengths
+/// that disagree, makes build() give back LengthMismatch.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::data::{Buffer, BufferBuilder};
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let texture = vec![0.5f32; 512 * 512 * 4];
+///
+///     // the kernel only reads it, and the host never touches it again
+///     let tex_buf: Buffer<f32> = BufferBuilder::new()
+///         .read_only()
+///         .host_no_access()
+///         .copy_from(&texture)
+///         .build(&mut env)?;
+///
+///     // the kernel only writes it, in memory the host can get at quickly
+///     let out_buf: Buffer<f32> = BufferBuilder::new()
+///         .len(1920 * 1080 * 4)
+///         .write_only()
+///         .host_read_only()
+///         .alloc_host()
+///         .build(&mut env)?;
+///     Ok(())
+/// }
+/// ```
+pub struct BufferBuilder<'a, T>
+where
+    T: Clone + 'static,
+{
+    len: Option<usize>,
+    access: cl_mem_flags,
+    host_access: cl_mem_flags,
+    alloc_host: bool,
+    host: Host<'a, T>,
+}
+
+/// Where a BufferBuilder's buffer gets its first contents from.
+enum Host<'a, T> {
+    Nothing,
+    Copy(&'a [T]),
+    Use(Vec<T>),
+}
+
+impl<T> Default for BufferBuilder<'_, T>
 where
     T: Clone + 'static,
 {
-    unsafe {
-        let size = data.len() * std::mem::size_of::<T>();
-        let buf = clCreateBuffer(
-            env.context,
-            CL_MEM_READ_WRITE.into(),
-            size,
-            std::ptr::null_mut(),
-            &mut env.err,
-        );
-        if env.err != 0 {
-            panic!("OpenCL error: {}", env.err);
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, T> BufferBuilder<'a, T>
+where
+    T: Clone + 'static,
+{
+    /// An empty, read-write buffer, like Buffer::new() makes.
+    pub fn new() -> Self {
+        BufferBuilder {
+            len: None,
+            access: CL_MEM_READ_WRITE.into(),
+            host_access: 0,
+            alloc_host: false,
+            host: Host::Nothing,
         }
+    }
 
-        Buffer {
-            buffer: buf,
-            data: data.to_vec(),
+    /// How many elements the buffer holds, when it isn't made from data.
+    pub fn len(mut self, len: usize) -> Self {
+        self.len = Some(len);
+        self
+    }
+
+    /// Kernels only read the buffer (CL_MEM_READ_ONLY).
+    pub fn read_only(mut self) -> Self {
+        self.access = CL_MEM_READ_ONLY.into();
+        self
+    }
+
+    /// Kernels only write the buffer (CL_MEM_WRITE_ONLY).
+    pub fn write_only(mut self) -> Self {
+        self.access = CL_MEM_WRITE_ONLY.into();
+        self
+    }
+
+    /// The host only reads the buffer (CL_MEM_HOST_READ_ONLY).
+    pub fn host_read_only(mut self) -> Self {
+        self.host_access = CL_MEM_HOST_READ_ONLY.into();
+        self
+    }
+
+    /// The host only writes the buffer (CL_MEM_HOST_WRITE_ONLY).
+    pub fn host_write_only(mut self) -> Self {
+        self.host_access = CL_MEM_HOST_WRITE_ONLY.into();
+        self
+    }
+
+    /// The host never reads or writes the buffer after it is made (CL_MEM_HOST_NO_ACCESS).
+    pub fn host_no_access(mut self) -> Self {
+        self.host_access = CL_MEM_HOST_NO_ACCESS.into();
+        self
+    }
+
+    /// Put the buffer in memory the host can get at (CL_MEM_ALLOC_HOST_PTR), which is
+    /// usually pinned and quicker to transfer. Can't be used with use_host(); build() gives
+    /// back AllocAndUseHost if both are.
+    pub fn alloc_host(mut self) -> Self {
+        self.alloc_host = true;
+        self
+    }
+
+    /// Fill the buffer with data when it's made (CL_MEM_COPY_HOST_PTR).
+    pub fn copy_from(mut self, data: &'a [T]) -> Self {
+        self.host = Host::Copy(data);
+        self
+    }
+
+    /// Use data as the buffer's memory (CL_MEM_USE_HOST_PTR). The driver may still cache it
+    /// on the device, so go through the buffer's transfers rather than the Vec.
+    /// The Vec is freed once OpenCL destroys the buffer, which can be after the Buffer is
+    /// dropped if commands using it are still queued, and on a driver thread, hence Send.
+    pub fn use_host(mut self, data: Vec<T>) -> Self
+    where
+        T: Send,
+    {
+        self.host = Host::Use(data);
+        self
+    }
+
+    /// Make the buffer.
+    pub fn build(self, env: &mut Env) -> Result<Buffer<T>, ClError> {
+        // OpenCL doesn't allow both, as the buffer can't be in its own memory and in the Vec
+        if self.alloc_host && matches!(self.host, Host::Use(_)) {
+            return Err(ClError::AllocAndUseHost);
+        }
+        let mut flags = self.access | self.host_access;
+        if self.alloc_host {
+            flags |= cl_mem_flags::from(CL_MEM_ALLOC_HOST_PTR);
+        }
+        let data_len = match &self.host {
+            Host::Nothing => None,
+            Host::Copy(data) => Some(data.len()),
+            Host::Use(data) => Some(data.len()),
+        };
+        // the driver reads (and with use_host(), writes) the whole length through the pointer
+        let len = match (self.len, data_len) {
+            (Some(len), Some(found)) if len != found => {
+                return Err(ClError::LengthMismatch {
+                    expected: len,
+                    found,
+                });
+            }
+            (len, data_len) => data_len.or(len).unwrap_or(0),
+        };
+        match self.host {
+            Host::Nothing => buffer_write(env, flags, len, std::ptr::null_mut()),
+            Host::Copy(data) => {
+                flags |= cl_mem_flags::from(CL_MEM_COPY_HOST_PTR);
+                let ptr = data.as_ptr() as *mut std::ffi::c_void;
+                buffer_write(env, flags, len, ptr)
+            }
+            Host::Use(data) => {
+                flags |= cl_mem_flags::from(CL_MEM_USE_HOST_PTR);
+                // boxed, so the destructor callback gets a thin pointer it can rebuild the Vec from
+                let mut data = Box::new(data);
+                let ptr = data.as_mut_ptr() as *mut std::ffi::c_void;
+                let buf = buffer_write(env, flags, len, ptr)?;
+                let data = Box::into_raw(data);
+                unsafe {
+                    let err = clSetMemObjectDestructorCallback(
+                        buf.buffer,
+                        Some(free_host::<T>),
+                        data as *mut std::ffi::c_void,
+                    );
+                    if let Err(e) = check("clSetMemObjectDestructorCallback", err) {
+                        // nothing was queued on it yet, so it's gone once released
+                        drop(buf);
+                        drop(Box::from_raw(data));
+                        return Err(e);
+                    }
+                }
+                Ok(buf)
+            }
         }
     }
 }
 
-/// Send the buffer, and the data supposed to be in the buffer, to the GPU.
-/// Used by Buffer.to().
-fn to_gpu<T>(env: &mut Env, data: &mut [T], buffer: &mut Buffer<T>)
+/// Frees a use_host() Vec when OpenCL destroys the buffer using it, after every command
+/// on it is done.
+unsafe extern "C" fn free_host<T>(_buffer: cl_mem, data: *mut std::ffi::c_void) {
+    unsafe { drop(Box::from_raw(data as *mut Vec<T>)) }
+}
+
+/// Create a buffer of len elements on the GPU. This function is used implicitly by Buffer::new()
+/// and BufferBuilder. You don't have to call it.
+fn buffer_write<T>(
+    env: &mut Env,
+    flags: cl_mem_flags,
+    len: usize,
+    host: *mut std::ffi::c_void,
+) -> Result<Buffer<T>, ClError>
 where
     T: Clone + 'static,
 {
     unsafe {
-        let size = data.len() * std::mem::size_of::<T>();
-        clEnqueueWriteBuffer(
+        let size = len * std::mem::size_of::<T>();
+        let mut err = 0;
+        let buf = clCreateBuffer(env.context, flags, size, host, &mut err);
+        check("clCreateBuffer", err).map_err(|e| e.with_size(size))?;
+
+        Ok(Buffer {
+            buffer: buf,
+            len,
+            mirror: None,
+            flags: flags & !cl_mem_flags::from(CL_MEM_USE_HOST_PTR | CL_MEM_COPY_HOST_PTR),
+        })
+    }
+}
+
+/// Send the buffer, and the data supposed to be in the buffer, to the GPU, offset elements in.
+/// Used by Buffer.to() and Buffer.to_async(), which check that it fits first.
+/// If blocking is CL_TRUE the host waits until the data is copied, so data can be dropped
+/// straight after. If not, data must live until the event is done.
+fn to_gpu<T>(
+    env: &mut Env,
+    data: &[T],
+    buffer: &Buffer<T>,
+    offset: usize,
+    after: &[&Event],
+    blocking: cl_bool,
+) -> Result<Event, ClError>
+where
+    T: Clone + 'static,
+{
+    let after = WaitList::new(after);
+    unsafe {
+        let size = std::mem::size_of_val(data);
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueWriteBuffer(
             env.queue,
             buffer.buffer,
-            CL_TRUE,
-            0,
+            blocking,
+            offset * std::mem::size_of::<T>(),
             size,
             data.as_ptr() as *const _,
-            0,
-            std::ptr::null_mut(),
-            std::ptr::null_mut(),
+            after.len(),
+            after.as_ptr(),
+            &mut event,
         );
-        if env.err != 0 {
-            panic!("OpenCL error: {}", env.err);
+        check("clEnqueueWriteBuffer", err).map_err(|e| e.with_size(size))?;
+        let event = Event::new(event);
+        if blocking == CL_FALSE {
+            flush(env.queue, &event)?;
         }
+        env.record(Command::Transfer(Direction::Write, size), &event);
+        Ok(event)
+    }
+}
+
+/// Copy len elements from src to dst on the device, at offsets in elements. Used by
+/// Buffer.copy_from(), Buffer.copy_range() and Buffer.resize(), which check that it fits first.
+fn copy_gpu<T>(
+    env: &mut Env,
+    src: &Buffer<T>,
+    src_offset: usize,
+    dst: &Buffer<T>,
+    dst_offset: usize,
+    len: usize,
+    after: &[&Event],
+) -> Result<Event, ClError>
+where
+    T: Clone + 'static,
+{
+    let after = WaitList::new(after);
+    let size = std::mem::size_of::<T>();
+    unsafe {
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueCopyBuffer(
+            env.queue,
+            src.buffer,
+            dst.buffer,
+            src_offset * size,
+            dst_offset * size,
+            len * size,
+            after.len(),
+            after.as_ptr(),
+            &mut event,
+        );
+        check("clEnqueueCopyBuffer", err).map_err(|e| e.with_size(len * size))?;
+        check("clFlush", clFlush(env.queue))?;
+        let event = Event::new(event);
+        env.record(Command::Transfer(Direction::Copy, len * size), &event);
+        Ok(event)
     }
 }
 
-/// Get data from the GPU, from a specific buffer.
-/// Used by Buffer.from().
-fn from_gpu<T>(env: &mut Env, data: &mut [T], buf: &mut Buffer<T>)
+/// An event that completes once everything in after has, for commands with nothing to do.
+fn marker(env: &mut Env, after: &WaitList) -> Result<Event, ClError> {
+    unsafe {
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueMarkerWithWaitList(env.queue, after.len(), after.as_ptr(), &mut event);
+        check("clEnqueueMarkerWithWaitList", err)?;
+        Ok(Event::new(event))
+    }
+}
+
+/// Set len elements of buffer, offset elements in, to value. Used by Buffer.fill() and
+/// Buffer.fill_range(). The driver copies value when it's enqueued, so it can go right away.
+fn fill_gpu<T>(
+    env: &mut Env,
+    buffer: &Buffer<T>,
+    value: T,
+    offset: usize,
+    len: usize,
+    after: &[&Event],
+) -> Result<Event, ClError>
 where
     T: Copy + 'static,
 {
+    let size = std::mem::size_of::<T>();
+    if !size.is_power_of_two() || size > 128 {
+        return Err(ClError::FillPatternSize { size });
+    }
+    let after = WaitList::new(after);
     unsafe {
-        let size = data.len() * std::mem::size_of::<T>();
-        clEnqueueReadBuffer(
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueFillBuffer(
+            env.queue,
+            buffer.buffer,
+            &value as *const T as *const _,
+            size,
+            offset * size,
+            len * size,
+            after.len(),
+            after.as_ptr(),
+            &mut event,
+        );
+        check("clEnqueueFillBuffer", err).map_err(|e| e.with_size(len * size))?;
+        check("clFlush", clFlush(env.queue))?;
+        let event = Event::new(event);
+        env.record(Command::Transfer(Direction::Fill, len * size), &event);
+        Ok(event)
+    }
+}
+
+/// Submit a non-blocking transfer, so it runs without anything waiting on it. If that
+/// fails, wait for it anyway before the host memory it uses can be given back.
+pub(crate) fn flush(queue: cl_command_queue, event: &Event) -> Result<(), ClError> {
+    let flushed = check("clFlush", unsafe { clFlush(queue) });
+    if flushed.is_err() {
+        let _ = event.wait();
+    }
+    flushed
+}
+
+/// Get data from the GPU, from a specific buffer, offset elements in.
+/// Used by Buffer.from() and Buffer.from_async(). blocking works like in to_gpu().
+fn from_gpu<T>(
+    env: &mut Env,
+    data: &mut [T],
+    buf: &Buffer<T>,
+    offset: usize,
+    after: &[&Event],
+    blocking: cl_bool,
+) -> Result<Event, ClError>
+where
+    T: Copy + 'static,
+{
+    let after = WaitList::new(after);
+    unsafe {
+        let size = std::mem::size_of_val(data);
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueReadBuffer(
             env.queue,
             buf.buffer,
-            CL_TRUE,
-            0,
+            blocking,
+            offset * std::mem::size_of::<T>(),
             size,
             data.as_mut_ptr() as *mut _,
-            0,
-            std::ptr::null_mut(),
-            std::ptr::null_mut(),
+            after.len(),
+            after.as_ptr(),
+            &mut event,
         );
 
-        if env.err != 0 {
-            panic!("OpenCL error: {}", env.err);
+        check("clEnqueueReadBuffer", err).map_err(|e| e.with_size(size))?;
+        let event = Event::new(event);
+        if blocking == CL_FALSE {
+            flush(env.queue, &event)?;
         }
+        env.record(Command::Transfer(Direction::Read, size), &event);
+        Ok(event)
     }
 }
 
@@ -166,13 +1148,13 @@ where
 ///
 /// # Examples
 ///
-/// ```rust
+/// ```rust,no_run
 /// use obrah::data::Buffer;
 /// use obrah::runtime::Env;
 /// {
-///     let mut env = Env::new(0, 0);
-///     let mut data = vec![1.0f32; 10];
-///     let buf = Buffer::new(&mut env, &mut data);
+///     let mut env = Env::new(0, 0).unwrap();
+///     let data = vec![1.0f32; 10];
+///     let buf = Buffer::new(&mut env, &data).unwrap();
 /// } // <- automatically dropped here
 /// ```
 fn cleanvar<T>(buf: &mut Buffer<T>)
@@ -183,3 +1165,68 @@ where
         clReleaseMemObject(buf.buffer);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn len_has_to_match() {
+        assert!(check_len(1024, 1024).is_ok());
+        assert!(matches!(
+            check_len(1024, 1025),
+            Err(ClError::LengthMismatch {
+                expected: 1024,
+                found: 1025
+            })
+        ));
+    }
+
+    #[test]
+    fn range_fits_up_to_the_end() {
+        assert!(check_range(1024, 1000, 24).is_ok());
+        assert!(check_range(1024, 1024, 0).is_ok());
+        assert!(matches!(
+            check_range(1024, 1000, 25),
+            Err(ClError::OutOfBounds {
+                offset: 1000,
+                len: 25,
+                buffer_len: 1024
+            })
+        ));
+        assert!(check_range(1024, 1025, 0).is_err());
+    }
+
+    #[test]
+    fn range_overflow() {
+        assert!(check_range(1024, usize::MAX, 2).is_err());
+        assert!(check_range(usize::MAX, usize::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn rect_fits() {
+        // a 4x4 block in the bottom right corner of an 8x8 image
+        assert!(check_rect(64, 8, Rect::new(4, 4, 4, 4)).is_ok());
+        // one row too many
+        assert!(check_rect(64, 8, Rect::new(4, 5, 4, 4)).is_err());
+    }
+
+    #[test]
+    fn rect_wider_than_a_row() {
+        // would fit in the buffer, but runs into the next row
+        assert!(check_rect(64, 8, Rect::new(6, 0, 4, 2)).is_err());
+        assert!(check_rect(64, 8, Rect::new(0, 0, 9, 1)).is_err());
+    }
+
+    #[test]
+    fn empty_rect_always_fits() {
+        assert!(check_rect(64, 8, Rect::new(100, 100, 0, 0)).is_ok());
+        assert!(check_rect(64, 8, Rect::new(100, 100, 4, 0)).is_ok());
+    }
+
+    #[test]
+    fn rect_overflow() {
+        assert!(check_rect(64, usize::MAX, Rect::new(0, 2, 1, 1)).is_err());
+        assert!(check_rect(64, 8, Rect::new(usize::MAX, 0, 2, 1)).is_err());
+    }
+}
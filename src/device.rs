@@ -0,0 +1,468 @@
+use crate::error::check;
+use crate::runtime::{
+    ClError, device_name, device_string, device_value, info_string, work_item_sizes,
+};
+use obwio::*;
+use std::fmt;
+
+/// The kinds of device a DeviceSelector can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Gpu,
+    Cpu,
+    Accelerator,
+    /// Devices that can't run OpenCL C, only built-in kernels.
+    Custom,
+    All,
+}
+
+impl DeviceType {
+    fn bits(self) -> cl_device_type {
+        match self {
+            DeviceType::Gpu => CL_DEVICE_TYPE_GPU.into(),
+            DeviceType::Cpu => CL_DEVICE_TYPE_CPU.into(),
+            DeviceType::Accelerator => CL_DEVICE_TYPE_ACCELERATOR.into(),
+            DeviceType::Custom => CL_DEVICE_TYPE_CUSTOM.into(),
+            DeviceType::All => CL_DEVICE_TYPE_ALL.into(),
+        }
+    }
+
+    fn from_bits(bits: cl_device_type) -> Self {
+        [DeviceType::Gpu, DeviceType::Accelerator, DeviceType::Cpu]
+            .into_iter()
+            .find(|t| bits & t.bits() != 0)
+            .unwrap_or(DeviceType::Custom)
+    }
+
+    /// What a device of this type is, for messages.
+    fn describe(self) -> &'static str {
+        match self {
+            DeviceType::Gpu => "a GPU",
+            DeviceType::Cpu => "a CPU",
+            DeviceType::Accelerator => "an accelerator",
+            DeviceType::Custom => "a custom device",
+            DeviceType::All => "any device",
+        }
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DeviceType::Gpu => "GPU",
+            DeviceType::Cpu => "CPU",
+            DeviceType::Accelerator => "Accelerator",
+            DeviceType::Custom => "Custom",
+            DeviceType::All => "All",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// DeviceSelector picks a device by what it is and what it can do, instead of by index.
+/// Every filter set has to match; of the devices that are left, the best one is picked.
+/// GPUs are preferred over accelerators, and accelerators over CPUs, then the one with the
+/// most compute units times clock speed, then the one with the most memory.
+/// When nothing qualifies, the error says why each device was turned down.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::device::{DeviceSelector, DeviceType};
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     // any device with doubles and 2 GiB of memory, GPU if there is one
+///     let env = Env::select(&DeviceSelector::new().min_memory(2 << 30).fp64())?;
+///
+///     // CI only has PoCL
+///     let env = Env::select(&DeviceSelector::new().device_type(DeviceType::Cpu))?;
+///
+///     let env = Env::select(
+///         &DeviceSelector::new()
+///             .device_type(DeviceType::Gpu)
+///             .vendor("NVIDIA")
+///             .extension("cl_khr_fp16"),
+///     )?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeviceSelector {
+    device_type: DeviceType,
+    name: Option<String>,
+    vendor: Option<String>,
+    min_memory: u64,
+    extensions: Vec<String>,
+    fp64: bool,
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceSelector {
+    /// A selector that takes any device.
+    pub fn new() -> Self {
+        DeviceSelector {
+            device_type: DeviceType::All,
+            name: None,
+            vendor: None,
+            min_memory: 0,
+            extensions: Vec::new(),
+            fp64: false,
+        }
+    }
+    /// Only take devices of this type.
+    pub fn device_type(mut self, device_type: DeviceType) -> Self {
+        self.device_type = device_type;
+        self
+    }
+    /// Only take devices with this in their name. Case doesn't matter.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    /// Only take devices with this in their vendor's name. Case doesn't matter.
+    pub fn vendor(mut self, vendor: &str) -> Self {
+        self.vendor = Some(vendor.to_string());
+        self
+    }
+    /// Only take devices with at least this many bytes of global memory.
+    pub fn min_memory(mut self, bytes: u64) -> Self {
+        self.min_memory = bytes;
+        self
+    }
+    /// Only take devices that support an extension, like "cl_khr_fp16".
+    /// Can be used more than once.
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.extensions.push(extension.to_string());
+        self
+    }
+    /// Only take devices that can do double precision.
+    pub fn fp64(mut self) -> Self {
+        self.fp64 = true;
+        self
+    }
+
+    /// Find the best device that matches, on any platform. Env::select() uses this.
+    pub fn select(&self) -> Result<Device, ClError> {
+        let mut best: Option<(Device, (u8, u64, u64))> = None;
+        let mut rejected = Vec::new();
+        for device in devices()? {
+            match self.reject(&device) {
+                Some(reason) => rejected.push((device.name(), reason)),
+                None => {
+                    let score = score(&device);
+                    if best.is_none_or(|(_, best)| score > best) {
+                        best = Some((device, score));
+                    }
+                }
+            }
+        }
+        best.map(|(device, _)| device)
+            .ok_or(ClError::NoMatchingDevice { rejected })
+    }
+
+    /// reject() says why a device doesn't match, or None if it does.
+    fn reject(&self, device: &Device) -> Option<String> {
+        let kind: cl_device_type = device_value(device.device, CL_DEVICE_TYPE);
+        if kind & self.device_type.bits() == 0 {
+            return Some(format!(
+                "is {}, not {}",
+                DeviceType::from_bits(kind).describe(),
+                self.device_type.describe()
+            ));
+        }
+        if let Some(name) = &self.name
+            && !contains(&device.name(), name)
+        {
+            return Some(format!("name doesn't contain \"{name}\""));
+        }
+        if let Some(vendor) = &self.vendor
+            && !contains(&device.vendor(), vendor)
+        {
+            return Some(format!(
+                "vendor \"{}\" doesn't contain \"{vendor}\"",
+                device.vendor()
+            ));
+        }
+        let memory = device.global_mem();
+        if memory < self.min_memory {
+            return Some(format!(
+                "has {} MiB of memory, needs {} MiB",
+                memory >> 20,
+                self.min_memory >> 20
+            ));
+        }
+        let extensions = device.extensions();
+        if let Some(missing) = self.extensions.iter().find(|e| !extensions.contains(e)) {
+            return Some(format!("doesn't support {missing}"));
+        }
+        if self.fp64 && !device.fp64() {
+            return Some("doesn't support double precision".to_string());
+        }
+        None
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// score() ranks devices for DeviceSelector; higher is better.
+fn score(device: &Device) -> (u8, u64, u64) {
+    let rank = match device.device_type() {
+        DeviceType::Gpu => 3,
+        DeviceType::Accelerator => 2,
+        DeviceType::Cpu => 1,
+        _ => 0,
+    };
+    let compute = device.compute_units() as u64 * device.clock_mhz() as u64;
+    (rank, compute, device.global_mem())
+}
+
+/// platforms() lists every OpenCL platform that's installed.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::device::platforms;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     for platform in platforms()? {
+///         println!("{} ({})", platform.name(), platform.version());
+///         for device in platform.devices()? {
+///             println!(
+///                 "  {}: {} compute units at {} MHz, {} MiB",
+///                 device.name(),
+///                 device.compute_units(),
+///                 device.clock_mhz(),
+///                 device.global_mem() >> 20
+///             );
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn platforms() -> Result<Vec<Platform>, ClError> {
+    Ok(platform_ids()?
+        .into_iter()
+        .map(|platform| Platform { platform })
+        .collect())
+}
+
+/// devices() lists every device on every platform.
+pub fn devices() -> Result<Vec<Device>, ClError> {
+    let mut devices = Vec::new();
+    for platform in platforms()? {
+        devices.extend(platform.devices()?);
+    }
+    Ok(devices)
+}
+
+/// get_devices() prints every platform and the devices on it, with their indices for
+/// Env::new(). See platforms() for getting them as values instead.
+pub fn get_devices() -> Result<(), ClError> {
+    for (p_idx, platform) in platforms()?.iter().enumerate() {
+        println!("Platform {}: {}", p_idx, platform.name());
+        for (d_idx, device) in platform.devices()?.iter().enumerate() {
+            println!("  Device {}: {}", d_idx, device.name());
+        }
+    }
+    Ok(())
+}
+
+/// Platform is an OpenCL implementation, like a vendor's driver or PoCL.
+/// It's returned by platforms().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Platform {
+    pub platform: cl_platform_id,
+}
+
+impl Platform {
+    pub fn name(&self) -> String {
+        self.string(CL_PLATFORM_NAME)
+    }
+    pub fn vendor(&self) -> String {
+        self.string(CL_PLATFORM_VENDOR)
+    }
+    /// The OpenCL version, like "OpenCL 3.0 CUDA 12.4.89".
+    pub fn version(&self) -> String {
+        self.string(CL_PLATFORM_VERSION)
+    }
+    /// "FULL_PROFILE" or "EMBEDDED_PROFILE".
+    pub fn profile(&self) -> String {
+        self.string(CL_PLATFORM_PROFILE)
+    }
+    pub fn extensions(&self) -> Vec<String> {
+        split(&self.string(CL_PLATFORM_EXTENSIONS))
+    }
+    /// Every device on the platform.
+    pub fn devices(&self) -> Result<Vec<Device>, ClError> {
+        self.devices_of(DeviceType::All)
+    }
+    /// The devices of one type on the platform.
+    pub fn devices_of(&self, device_type: DeviceType) -> Result<Vec<Device>, ClError> {
+        Ok(device_ids(self.platform, device_type.bits())?
+            .into_iter()
+            .map(|device| Device {
+                device,
+                platform: self.platform,
+            })
+            .collect())
+    }
+
+    fn string(&self, param: cl_platform_info) -> String {
+        unsafe {
+            let mut size = 0;
+            clGetPlatformInfo(self.platform, param, 0, std::ptr::null_mut(), &mut size);
+            let mut buf = vec![0u8; size];
+            clGetPlatformInfo(
+                self.platform,
+                param,
+                size,
+                buf.as_mut_ptr() as *mut _,
+                std::ptr::null_mut(),
+            );
+            info_string(buf)
+        }
+    }
+}
+
+/// Device is one device on a platform. It's returned by devices() and Platform::devices().
+/// The accessors ask the driver every time, so keep what's needed rather than calling
+/// them in a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub device: cl_device_id,
+    pub platform: cl_platform_id,
+}
+
+impl Device {
+    pub fn name(&self) -> String {
+        device_name(self.device)
+    }
+    pub fn vendor(&self) -> String {
+        device_string(self.device, CL_DEVICE_VENDOR)
+    }
+    /// The OpenCL version the device supports, like "OpenCL 3.0".
+    pub fn version(&self) -> String {
+        device_string(self.device, CL_DEVICE_VERSION)
+    }
+    pub fn driver_version(&self) -> String {
+        device_string(self.device, CL_DRIVER_VERSION)
+    }
+    /// The OpenCL C version kernels can be written in, like "OpenCL C 1.2".
+    pub fn opencl_c_version(&self) -> String {
+        device_string(self.device, CL_DEVICE_OPENCL_C_VERSION)
+    }
+    pub fn device_type(&self) -> DeviceType {
+        let bits: cl_device_type = device_value(self.device, CL_DEVICE_TYPE);
+        DeviceType::from_bits(bits)
+    }
+    pub fn platform(&self) -> Platform {
+        Platform {
+            platform: self.platform,
+        }
+    }
+    pub fn compute_units(&self) -> u32 {
+        device_value(self.device, CL_DEVICE_MAX_COMPUTE_UNITS)
+    }
+    /// The max clock speed in MHz.
+    pub fn clock_mhz(&self) -> u32 {
+        device_value(self.device, CL_DEVICE_MAX_CLOCK_FREQUENCY)
+    }
+    /// Global memory, in bytes.
+    pub fn global_mem(&self) -> u64 {
+        device_value(self.device, CL_DEVICE_GLOBAL_MEM_SIZE)
+    }
+    /// Local memory per work-group, in bytes.
+    pub fn local_mem(&self) -> u64 {
+        device_value(self.device, CL_DEVICE_LOCAL_MEM_SIZE)
+    }
+    /// The biggest constant buffer, in bytes.
+    pub fn constant_mem(&self) -> u64 {
+        device_value(self.device, CL_DEVICE_MAX_CONSTANT_BUFFER_SIZE)
+    }
+    /// The biggest single buffer that can be made, in bytes.
+    pub fn max_alloc(&self) -> u64 {
+        device_value(self.device, CL_DEVICE_MAX_MEM_ALLOC_SIZE)
+    }
+    /// The most work-items in one work-group.
+    pub fn max_work_group_size(&self) -> usize {
+        device_value(self.device, CL_DEVICE_MAX_WORK_GROUP_SIZE)
+    }
+    /// The most work-items in a work-group in each dimension, for as many dimensions as the
+    /// device has (at least 3). Empty if the device couldn't be asked.
+    pub fn max_work_item_sizes(&self) -> Vec<usize> {
+        work_item_sizes(self.device).unwrap_or_default()
+    }
+    pub fn image_support(&self) -> bool {
+        device_value::<cl_bool>(self.device, CL_DEVICE_IMAGE_SUPPORT) != 0
+    }
+    /// Whether the device can do double precision.
+    pub fn fp64(&self) -> bool {
+        device_value::<cl_device_fp_config>(self.device, CL_DEVICE_DOUBLE_FP_CONFIG) != 0
+    }
+    pub fn extensions(&self) -> Vec<String> {
+        split(&device_string(self.device, CL_DEVICE_EXTENSIONS))
+    }
+    pub fn has_extension(&self, extension: &str) -> bool {
+        self.extensions().iter().any(|e| e == extension)
+    }
+}
+
+fn split(list: &str) -> Vec<String> {
+    list.split_whitespace().map(str::to_string).collect()
+}
+
+/// platform_ids() gets every platform. No platforms at all isn't an error, just none.
+pub(crate) fn platform_ids() -> Result<Vec<cl_platform_id>, ClError> {
+    unsafe {
+        let mut num_platforms: cl_uint = 0;
+        let err = clGetPlatformIDs(0, std::ptr::null_mut(), &mut num_platforms);
+        // CL_PLATFORM_NOT_FOUND_KHR, from the ICD loader
+        if err == -1001 || num_platforms == 0 {
+            return Ok(Vec::new());
+        }
+        check("clGetPlatformIDs", err)?;
+        let mut platforms = vec![std::ptr::null_mut(); num_platforms as usize];
+        let err = clGetPlatformIDs(num_platforms, platforms.as_mut_ptr(), std::ptr::null_mut());
+        check("clGetPlatformIDs", err)?;
+        Ok(platforms)
+    }
+}
+
+/// device_ids() gets the devices of a type on a platform. None of that type isn't an error.
+pub(crate) fn device_ids(
+    platform: cl_platform_id,
+    device_type: cl_device_type,
+) -> Result<Vec<cl_device_id>, ClError> {
+    unsafe {
+        let mut num_devices: cl_uint = 0;
+        let err = clGetDeviceIDs(
+            platform,
+            device_type,
+            0,
+            std::ptr::null_mut(),
+            &mut num_devices,
+        );
+        if err == CL_DEVICE_NOT_FOUND || num_devices == 0 {
+            return Ok(Vec::new());
+        }
+        check("clGetDeviceIDs", err)?;
+        let mut devices = vec![std::ptr::null_mut(); num_devices as usize];
+        let err = clGetDeviceIDs(
+            platform,
+            device_type,
+            num_devices,
+            devices.as_mut_ptr(),
+            std::ptr::null_mut(),
+        );
+        check("clGetDeviceIDs", err)?;
+        Ok(devices)
+    }
+}
@@ -0,0 +1,462 @@
+use crate::build::BuildLog;
+use obwio::cl_int;
+use std::fmt;
+
+// cl_errors! writes out ClError, with a variant for every OpenCL status code, and the
+// From<i32>/code()/Display parts for them. Variants that aren't status codes go in `others`,
+// and are handled by hand.
+macro_rules! cl_errors {
+    (
+        statuses {
+            $($(#[$meta:meta])* $name:ident = $code:literal => $msg:literal,)*
+        }
+        others {
+            $($others:tt)*
+        }
+    ) => {
+        /// Every error OBRAH can give back.
+        /// Errors from OpenCL calls come wrapped in ClError::Call, which says which call failed.
+        /// Use kind() to get to the status underneath, and code() for the raw status code.
+        #[derive(Debug)]
+        pub enum ClError {
+            $($(#[$meta])* $name,)*
+            $($others)*
+        }
+
+        impl ClError {
+            /// The OpenCL status code for status variants.
+            fn status_code(&self) -> Option<i32> {
+                match self {
+                    $(Self::$name => Some($code),)*
+                    _ => None,
+                }
+            }
+
+            /// The message for status variants.
+            fn status_message(&self) -> Option<&'static str> {
+                match self {
+                    $(Self::$name => Some($msg),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl From<i32> for ClError {
+            fn from(code: i32) -> Self {
+                match code {
+                    $($code => ClError::$name,)*
+                    _ => ClError::UnknownError(code),
+                }
+            }
+        }
+    };
+}
+
+cl_errors! {
+    statuses {
+        DeviceNotFound = -1 => "Device not found",
+        DeviceNotAvailable = -2 => "Device not available",
+        CompilerNotAvailable = -3 => "Compiler not available",
+        MemObjectAllocationFailure = -4 => "Failed to allocate memory for a buffer or image",
+        OutOfResources = -5 => "Out of resources",
+        /// Out of memory on the host.
+        OutOfMemory = -6 => "Out of memory",
+        ProfilingInfoNotAvailable = -7 => "Profiling info not available",
+        MemCopyOverlap = -8 => "Source and destination of the copy overlap",
+        ImageFormatMismatch = -9 => "Image formats don't match",
+        ImageFormatNotSupported = -10 => "Image format not supported",
+        BuildProgramFailed = -11 => "Failed to build program.",
+        MapFailure = -12 => "Failed to map buffer or image",
+        MisalignedSubBufferOffset = -13 => "Sub-buffer offset isn't aligned for the device",
+        ExecStatusErrorForEventsInWaitList = -14 => "An event in the wait list failed",
+        CompileProgramFailure = -15 => "Failed to compile program",
+        LinkerNotAvailable = -16 => "Linker not available",
+        LinkProgramFailure = -17 => "Failed to link program",
+        DevicePartitionFailed = -18 => "Failed to partition device",
+        KernelArgInfoNotAvailable = -19 => "Kernel arg info not available",
+        InvalidValue = -30 => "Invalid value",
+        InvalidDeviceType = -31 => "Invalid device type",
+        InvalidPlatform = -32 => "Invalid platform",
+        InvalidDevice = -33 => "Invalid device",
+        InvalidContext = -34 => "Invalid context",
+        InvalidQueueProperties = -35 => "Invalid queue properties",
+        InvalidCommandQueue = -36 => "Invalid command queue",
+        InvalidHostPtr = -37 => "Invalid host pointer",
+        InvalidMemObject = -38 => "Invalid buffer or image",
+        InvalidImageFormatDescriptor = -39 => "Invalid image format descriptor",
+        InvalidImageSize = -40 => "Invalid image size",
+        InvalidSampler = -41 => "Invalid sampler",
+        InvalidBinary = -42 => "Invalid program binary",
+        InvalidBuildOptions = -43 => "Invalid build options",
+        InvalidProgram = -44 => "Program not found",
+        InvalidProgramExecutable = -45 => "Program hasn't been built",
+        InvalidKernelName = -46 => "Kernel name not found",
+        InvalidKernelDefinition = -47 => "Kernel is defined differently for different devices",
+        InvalidKernel = -48 => "Invalid kernel",
+        InvalidArgIndex = -49 => "Invalid arg index",
+        InvalidArgValue = -50 => "Invalid arg value",
+        InvalidArgSize = -51 => "Invalid arg size",
+        InvalidKernelArgs = -52 => "Not all kernel args have been set",
+        InvalidWorkDimension = -53 => "Invalid number of work dimensions",
+        InvalidWorkGroupSize = -54 => "Invalid work-group size",
+        InvalidWorkItemSize = -55 => "Invalid work-item size",
+        InvalidGlobalOffset = -56 => "Invalid global offset",
+        InvalidEventWaitList = -57 => "Invalid event wait list",
+        InvalidEvent = -58 => "Invalid event",
+        InvalidOperation = -59 => "Invalid operation",
+        InvalidGlObject = -60 => "Invalid OpenGL object",
+        InvalidBufferSize = -61 => "Invalid buffer size",
+        InvalidMipLevel = -62 => "Invalid mip level",
+        InvalidGlobalWorkSize = -63 => "Invalid global work size",
+        InvalidProperty = -64 => "Invalid property",
+        InvalidImageDescriptor = -65 => "Invalid image descriptor",
+        InvalidCompilerOptions = -66 => "Invalid compiler options",
+        InvalidLinkerOptions = -67 => "Invalid linker options",
+        InvalidDevicePartitionCount = -68 => "Invalid device partition count",
+        InvalidPipeSize = -69 => "Invalid pipe size",
+        InvalidDeviceQueue = -70 => "Invalid device queue",
+        InvalidSpecId = -71 => "Invalid specialization constant id",
+        MaxSizeRestrictionExceeded = -72 => "Max size restriction exceeded",
+
+        // extensions
+        InvalidGlSharegroupReference = -1000 => "Invalid OpenGL sharegroup reference",
+        /// No OpenCL platforms are installed, from the ICD loader.
+        PlatformNotFound = -1001 => "No OpenCL platforms found",
+        InvalidD3D10Device = -1002 => "Invalid Direct3D 10 device",
+        InvalidD3D10Resource = -1003 => "Invalid Direct3D 10 resource",
+        D3D10ResourceAlreadyAcquired = -1004 => "Direct3D 10 resource already acquired",
+        D3D10ResourceNotAcquired = -1005 => "Direct3D 10 resource not acquired",
+        InvalidD3D11Device = -1006 => "Invalid Direct3D 11 device",
+        InvalidD3D11Resource = -1007 => "Invalid Direct3D 11 resource",
+        D3D11ResourceAlreadyAcquired = -1008 => "Direct3D 11 resource already acquired",
+        D3D11ResourceNotAcquired = -1009 => "Direct3D 11 resource not acquired",
+        InvalidDx9MediaAdapter = -1010 => "Invalid DirectX 9 media adapter",
+        InvalidDx9MediaSurface = -1011 => "Invalid DirectX 9 media surface",
+        Dx9MediaSurfaceAlreadyAcquired = -1012 => "DirectX 9 media surface already acquired",
+        Dx9MediaSurfaceNotAcquired = -1013 => "DirectX 9 media surface not acquired",
+        DevicePartitionFailedExt = -1057 => "Failed to partition device",
+        InvalidPartitionCount = -1058 => "Invalid partition count",
+        InvalidPartitionName = -1059 => "Invalid partition name",
+        EglResourceNotAcquired = -1092 => "EGL resource not acquired",
+        InvalidEglObject = -1093 => "Invalid EGL object",
+        InvalidAccelerator = -1094 => "Invalid accelerator",
+        InvalidAcceleratorType = -1095 => "Invalid accelerator type",
+        InvalidAcceleratorDescriptor = -1096 => "Invalid accelerator descriptor",
+        AcceleratorTypeNotSupported = -1097 => "Accelerator type not supported",
+        InvalidVaApiMediaAdapter = -1098 => "Invalid VA-API media adapter",
+        InvalidVaApiMediaSurface = -1099 => "Invalid VA-API media surface",
+        VaApiMediaSurfaceAlreadyAcquired = -1100 => "VA-API media surface already acquired",
+        VaApiMediaSurfaceNotAcquired = -1101 => "VA-API media surface not acquired",
+        InvalidCommandBuffer = -1138 => "Invalid command buffer",
+        InvalidSyncPointWaitList = -1139 => "Invalid sync point wait list",
+        IncompatibleCommandQueue = -1140 => "Incompatible command queue",
+        InvalidMutableCommand = -1141 => "Invalid mutable command",
+        InvalidSemaphore = -1142 => "Invalid semaphore",
+    }
+    others {
+        /// The program failed to build. Holds the build log of every device.
+        BuildFailed(BuildLog),
+        NonexistentPlatform,
+        /// No device matched a DeviceSelector. Holds each device's name, and why it didn't.
+        NoMatchingDevice { rejected: Vec<(String, String)> },
+        /// A program source or build option has a nul byte in it, so it can't go to OpenCL.
+        InteriorNul,
+        /// Env::program() was called before use_kernel() loaded a kernel.
+        NoKernelLoaded,
+        /// The local work-group size has more work-items than the kernel can run with on the device.
+        WorkGroupTooLarge { size: usize, max: usize },
+        /// One dimension of the local work-group size is bigger than the device allows.
+        WorkItemTooLarge { dim: usize, size: usize, max: usize },
+        /// The local work-group size doesn't divide the global size in one dimension, on a
+        /// device whose OpenCL C is older than 2.0.
+        UnevenWorkGroup { dim: usize, global: usize, local: usize },
+        /// The global size is 0 in one dimension, so there's nothing to run.
+        EmptyRange { dim: usize },
+        /// The local size or offset has a different number of dimensions than the global size.
+        RangeDimensions { expected: usize, found: usize },
+        /// The local work-group size is 0 in one dimension.
+        EmptyWorkGroup { dim: usize },
+        /// A whole-buffer transfer was given a slice of the wrong length.
+        LengthMismatch { expected: usize, found: usize },
+        /// A partial transfer doesn't fit in the buffer.
+        OutOfBounds { offset: usize, len: usize, buffer_len: usize },
+        /// There's no device with this index in the Env, which has count of them.
+        NonexistentDevice { index: usize, count: usize },
+        /// Env::from_devices() was given devices from more than one platform.
+        MixedPlatforms,
+        /// BufferBuilder was given both alloc_host() and use_host().
+        AllocAndUseHost,
+        /// Buffer::resize() was called on a buffer with a mirror, which would stop fitting.
+        MirroredResize,
+        /// A fill value isn't 1, 2, 4, 8, 16, 32, 64 or 128 bytes, which is all OpenCL can fill with.
+        FillPatternSize { size: usize },
+        /// A Split has a different number of weights than the Env has devices.
+        WeightCount { weights: usize, devices: usize },
+        /// Split::run() was given a 3-D range; it only splits 1-D and 2-D ones.
+        SplitDimensions { dims: usize },
+        /// Split::run()'s buffer isn't a whole number of elements per row of the range.
+        UnevenSplit { len: usize, rows: usize },
+        /// An OpenCL call failed. Says which call it was, and holds the error it gave back.
+        Call(Box<CallError>),
+        UnknownError(i32),
+    }
+}
+
+/// The details of a failed OpenCL call, held by ClError::Call.
+/// Besides the call, it says what it was working on, where that means something;
+/// the kernel it was for, the argument index, and the size of the data in bytes.
+#[derive(Debug)]
+pub struct CallError {
+    /// The name of the OpenCL function, like "clSetKernelArg".
+    pub call: &'static str,
+    pub kernel: Option<String>,
+    pub arg: Option<usize>,
+    pub size: Option<usize>,
+    pub error: ClError,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.call)?;
+        if let Some(kernel) = &self.kernel {
+            write!(f, " for kernel `{kernel}`")?;
+        }
+        if let Some(arg) = self.arg {
+            write!(f, " at arg {arg}")?;
+        }
+        if let Some(size) = self.size {
+            write!(f, " ({size} bytes)")?;
+        }
+        Ok(())
+    }
+}
+
+impl ClError {
+    /// Make the error for an OpenCL call that gave back a status code.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use obrah::runtime::ClError;
+    ///
+    /// let err = ClError::from_call("clEnqueueNDRangeKernel", -54);
+    /// assert!(matches!(err.kind(), ClError::InvalidWorkGroupSize));
+    /// assert_eq!(err.code(), Some(-54));
+    /// assert_eq!(err.call(), Some("clEnqueueNDRangeKernel"));
+    /// assert_eq!(err.to_string(), "clEnqueueNDRangeKernel failed");
+    ///
+    /// let source = std::error::Error::source(&err).unwrap();
+    /// assert_eq!(source.to_string(), "Invalid work-group size");
+    /// ```
+    pub fn from_call(call: &'static str, code: cl_int) -> ClError {
+        ClError::Call(Box::new(CallError {
+            call,
+            kernel: None,
+            arg: None,
+            size: None,
+            error: ClError::from(code),
+        }))
+    }
+
+    /// Say which kernel a failed call was for.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use obrah::runtime::ClError;
+    ///
+    /// let err = ClError::from_call("clSetKernelArg", -51)
+    ///     .with_kernel("raytrace")
+    ///     .with_arg(3)
+    ///     .with_size(16);
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "clSetKernelArg failed for kernel `raytrace` at arg 3 (16 bytes)"
+    /// );
+    /// assert!(matches!(err.kind(), ClError::InvalidArgSize));
+    /// ```
+    pub fn with_kernel(mut self, kernel: &str) -> Self {
+        if let Self::Call(call) = &mut self {
+            call.kernel = Some(kernel.to_string());
+        }
+        self
+    }
+
+    /// Say which kernel argument a failed call was for.
+    pub fn with_arg(mut self, arg: usize) -> Self {
+        if let Self::Call(call) = &mut self {
+            call.arg = Some(arg);
+        }
+        self
+    }
+
+    /// Say how many bytes a failed call was working with.
+    pub fn with_size(mut self, size: usize) -> Self {
+        if let Self::Call(call) = &mut self {
+            call.size = Some(size);
+        }
+        self
+    }
+
+    /// The error underneath any ClError::Call, for matching on.
+    pub fn kind(&self) -> &ClError {
+        match self {
+            Self::Call(call) => call.error.kind(),
+            _ => self,
+        }
+    }
+
+    /// The raw OpenCL status code, if this error came from one.
+    pub fn code(&self) -> Option<i32> {
+        match self.kind() {
+            Self::BuildFailed(_) => Some(-11),
+            Self::UnknownError(code) => Some(*code),
+            kind => kind.status_code(),
+        }
+    }
+
+    /// The OpenCL function that failed, if this error came from one.
+    pub fn call(&self) -> Option<&'static str> {
+        match self {
+            Self::Call(call) => Some(call.call),
+            _ => None,
+        }
+    }
+}
+
+/// check() turns the status code an OpenCL call gave back into a Result.
+pub(crate) fn check(call: &'static str, code: cl_int) -> Result<(), ClError> {
+    if code != 0 {
+        Err(ClError::from_call(call, code))
+    } else {
+        Ok(())
+    }
+}
+
+impl fmt::Display for ClError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(msg) = self.status_message() {
+            return write!(f, "{msg}");
+        }
+        match self {
+            Self::UnknownError(unkwn) => {
+                write!(f, "Unknown error code: {unkwn}")
+            }
+            Self::BuildFailed(log) => {
+                write!(f, "Failed to build program.\n{log}")
+            }
+            Self::NonexistentPlatform => {
+                write!(f, "Platform out of range")
+            }
+            Self::NoMatchingDevice { rejected } if rejected.is_empty() => {
+                write!(f, "No OpenCL devices found")
+            }
+            Self::NoMatchingDevice { rejected } => {
+                write!(f, "No device matched the selector:")?;
+                for (device, reason) in rejected {
+                    write!(f, "\n  `{device}` {reason}")?;
+                }
+                Ok(())
+            }
+            Self::InteriorNul => {
+                write!(f, "Program source or build options contain a nul byte")
+            }
+            Self::NoKernelLoaded => {
+                write!(f, "No kernel loaded; call use_kernel() first")
+            }
+            Self::WorkGroupTooLarge { size, max } => {
+                write!(
+                    f,
+                    "Work-group of {size} work-items is bigger than the max of {max}"
+                )
+            }
+            Self::WorkItemTooLarge { dim, size, max } => {
+                write!(
+                    f,
+                    "Work-group size {size} in dimension {dim} is bigger than the max of {max}"
+                )
+            }
+            Self::UnevenWorkGroup { dim, global, local } => {
+                write!(
+                    f,
+                    "Global size {global} in dimension {dim} isn't a multiple of the work-group size {local}"
+                )
+            }
+            Self::EmptyRange { dim } => {
+                write!(f, "Global size is 0 in dimension {dim}")
+            }
+            Self::RangeDimensions { expected, found } => {
+                write!(
+                    f,
+                    "Range has {found} dimensions where the global size has {expected}"
+                )
+            }
+            Self::EmptyWorkGroup { dim } => {
+                write!(f, "Work-group size is 0 in dimension {dim}")
+            }
+            Self::LengthMismatch { expected, found } => {
+                write!(
+                    f,
+                    "Slice of {found} elements doesn't match the buffer's {expected}"
+                )
+            }
+            Self::OutOfBounds {
+                offset,
+                len,
+                buffer_len,
+            } => {
+                write!(
+                    f,
+                    "Range of {len} elements at {offset} is outside the buffer's {buffer_len}"
+                )
+            }
+            Self::NonexistentDevice { index, count } => {
+                write!(f, "Device {index} out of range; the Env has {count}")
+            }
+            Self::MixedPlatforms => {
+                write!(f, "Devices aren't all on the same platform")
+            }
+            Self::AllocAndUseHost => {
+                write!(f, "Can't use both alloc_host() and use_host() on a buffer")
+            }
+            Self::MirroredResize => {
+                write!(
+                    f,
+                    "Can't resize a buffer with a mirror; take_mirror() first"
+                )
+            }
+            Self::FillPatternSize { size } => {
+                write!(
+                    f,
+                    "Can't fill with a {size} byte value; it has to be a power of two up to 128"
+                )
+            }
+            Self::WeightCount { weights, devices } => {
+                write!(f, "Split has {weights} weights for {devices} devices")
+            }
+            Self::SplitDimensions { dims } => {
+                write!(f, "Can't split a {dims}-D range; only 1-D and 2-D ones")
+            }
+            Self::UnevenSplit { len, rows } => {
+                write!(
+                    f,
+                    "Buffer of {len} elements doesn't divide into {rows} rows"
+                )
+            }
+            // the error itself is the source(), so error reporters don't print it twice
+            Self::Call(call) => {
+                write!(f, "{call}")
+            }
+            _ => unreachable!("status variants are handled above"),
+        }
+    }
+}
+
+impl std::error::Error for ClError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Call(call) => Some(&call.error),
+            _ => None,
+        }
+    }
+}
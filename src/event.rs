@@ -0,0 +1,392 @@
+use crate::error::{ClError, check};
+use obwio::*;
+use std::ffi::c_void;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Where a command is at, from Event::status().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventStatus {
+    Queued,
+    Submitted,
+    Running,
+    Complete,
+    /// The command failed, with this status code.
+    Failed(i32),
+}
+
+impl From<cl_int> for EventStatus {
+    fn from(status: cl_int) -> Self {
+        match status as u32 {
+            CL_QUEUED => EventStatus::Queued,
+            CL_SUBMITTED => EventStatus::Submitted,
+            CL_RUNNING => EventStatus::Running,
+            CL_COMPLETE => EventStatus::Complete,
+            _ => EventStatus::Failed(status),
+        }
+    }
+}
+
+/// Event is a handle to a command that was put on the queue, like a kernel launch.
+/// It's returned by the functions that don't block, like enqueue_kernel(), so the host can
+/// get on with other work and wait for the command later.
+/// Every enqueue also takes a list of Events it has to wait for, so commands can be chained
+/// on the device without the host waiting in between.
+/// Dropping an Event doesn't cancel or wait for the command.
+///
+/// An Event is also a Future, so it can be awaited instead of waited on. It's woken by the
+/// driver when the command is done, so it works with any executor and never blocks a thread.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::kernel::{Kernel, enqueue_kernel};
+/// use obrah::runtime::{ClError, Env};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let kernel = env
+///         .use_kernel("examples/raytrace_kernel.cl")?
+///         .program(&BuildOptions::new())?
+///         .make_kernel("raytrace")?;
+///     // ... set the arguments ...
+///
+///     let first = enqueue_kernel(&mut env, &kernel, (1920, 1080), &[])?;
+///     // the second frame only starts once the first is done
+///     let second = enqueue_kernel(&mut env, &kernel, (1920, 1080), &[&first])?;
+///     second.on_complete(|result| println!("frames done: {result:?}"))?;
+///     // do something else while the GPU works
+///     second.wait()?;
+///     Ok(())
+/// }
+///
+/// // the same, from async code
+/// async fn render(env: &mut Env, kernel: &Kernel) -> Result<(), ClError> {
+///     enqueue_kernel(env, kernel, (1920, 1080), &[])?.await
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Event {
+    pub event: cl_event,
+    waker: Arc<Mutex<Wake>>,
+}
+
+/// Wake is what an awaited Event shares with its completion callback.
+#[derive(Debug, Default)]
+struct Wake {
+    waker: Option<Waker>,
+    registered: bool,
+}
+
+// OpenCL events can be used from any thread
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+impl Event {
+    pub(crate) fn new(event: cl_event) -> Self {
+        Event {
+            event,
+            waker: Arc::default(),
+        }
+    }
+
+    /// Block until the command is done. Gives back the command's error if it failed.
+    pub fn wait(&self) -> Result<(), ClError> {
+        let err = unsafe { clWaitForEvents(1, &self.event) };
+        if err == CL_EXEC_STATUS_ERROR_FOR_EVENTS_IN_WAIT_LIST
+            && let EventStatus::Failed(code) = self.status()?
+        {
+            return Err(failed("clWaitForEvents", code));
+        }
+        check("clWaitForEvents", err)
+    }
+
+    /// When the command was queued, submitted, started and ended. It's only there once the
+    /// command is done, and if the Env is profiling; otherwise it's ProfilingInfoNotAvailable.
+    pub fn times(&self) -> Result<EventTimes, ClError> {
+        Ok(EventTimes {
+            queued: self.profiling_info(CL_PROFILING_COMMAND_QUEUED)?,
+            submitted: self.profiling_info(CL_PROFILING_COMMAND_SUBMIT)?,
+            started: self.profiling_info(CL_PROFILING_COMMAND_START)?,
+            ended: self.profiling_info(CL_PROFILING_COMMAND_END)?,
+        })
+    }
+
+    fn profiling_info(&self, param: cl_profiling_info) -> Result<u64, ClError> {
+        let mut time: cl_ulong = 0;
+        let err = unsafe {
+            clGetEventProfilingInfo(
+                self.event,
+                param,
+                std::mem::size_of::<cl_ulong>(),
+                &mut time as *mut cl_ulong as *mut _,
+                std::ptr::null_mut(),
+            )
+        };
+        check("clGetEventProfilingInfo", err)?;
+        Ok(time)
+    }
+
+    /// The command's result if it's done, without blocking.
+    fn result(&self) -> Option<Result<(), ClError>> {
+        match self.status() {
+            Ok(EventStatus::Complete) => Some(Ok(())),
+            Ok(EventStatus::Failed(code)) => Some(Err(failed("clGetEventInfo", code))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Where the command is at right now.
+    pub fn status(&self) -> Result<EventStatus, ClError> {
+        let mut status: cl_int = 0;
+        let err = unsafe {
+            clGetEventInfo(
+                self.event,
+                CL_EVENT_COMMAND_EXECUTION_STATUS,
+                std::mem::size_of::<cl_int>(),
+                &mut status as *mut cl_int as *mut _,
+                std::ptr::null_mut(),
+            )
+        };
+        check("clGetEventInfo", err)?;
+        Ok(EventStatus::from(status))
+    }
+
+    /// True once the command is done, whether it worked or not.
+    pub fn is_complete(&self) -> Result<bool, ClError> {
+        Ok(matches!(
+            self.status()?,
+            EventStatus::Complete | EventStatus::Failed(_)
+        ))
+    }
+
+    /// Call a function when the command is done. It's called from a thread of the driver,
+    /// not this one, so keep it short.
+    pub fn on_complete<F>(&self, f: F) -> Result<(), ClError>
+    where
+        F: FnOnce(Result<(), ClError>) + Send + 'static,
+    {
+        let callback: Box<Callback> = Box::new(Box::new(f));
+        let user_data = Box::into_raw(callback) as *mut c_void;
+        let err = unsafe {
+            clSetEventCallback(
+                self.event,
+                CL_COMPLETE as cl_int,
+                Some(complete_callback),
+                user_data,
+            )
+        };
+        if err != 0 {
+            // the driver won't call it, so it's ours to free
+            drop(unsafe { Box::from_raw(user_data as *mut Callback) });
+        }
+        check("clSetEventCallback", err)
+    }
+}
+
+/// failed() is the error for a command that finished with a failed status, wrapped in the
+/// call that found out, so wait(), .await and on_complete() all give back the same kind of error.
+fn failed(call: &'static str, code: cl_int) -> ClError {
+    ClError::from_call(call, code)
+}
+
+impl Future for Event {
+    type Output = Result<(), ClError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.result() {
+            return Poll::Ready(result);
+        }
+        let register = {
+            let mut wake = self.waker.lock().unwrap();
+            wake.waker = Some(cx.waker().clone());
+            !std::mem::replace(&mut wake.registered, true)
+        };
+        // the lock is let go first, as some drivers call back straight away on this thread
+        if register {
+            let shared = Arc::clone(&self.waker);
+            let registered = self.on_complete(move |_| {
+                let waker = shared.lock().unwrap().waker.take();
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            });
+            if let Err(e) = registered {
+                return Poll::Ready(Err(e));
+            }
+        }
+        // the callback may have run between the first check and storing the waker, and woken
+        // the waker from an earlier poll instead of this one
+        match self.result() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+type Callback = Box<dyn FnOnce(Result<(), ClError>) + Send>;
+
+/// complete_callback() is what OpenCL calls when an event is done. It runs the boxed
+/// function that on_complete() handed over.
+unsafe extern "C" fn complete_callback(_event: cl_event, status: cl_int, user_data: *mut c_void) {
+    let callback = unsafe { Box::from_raw(user_data as *mut Callback) };
+    if status < 0 {
+        callback(Err(failed("clSetEventCallback", status)));
+    } else {
+        callback(Ok(()));
+    }
+}
+
+/// Pending is an Event for a transfer that's still using host memory, returned by
+/// Buffer::to_async() and Buffer::from_async(). It owns that memory, and only gives it back
+/// from wait() or .await once the transfer is done.
+/// Dropping a Pending waits for the transfer before freeing the memory. mem::forget() on one
+/// leaks the memory, so the device can't end up writing to memory that was freed.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::data::Buffer;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let next_frame = Buffer::<f32>::with_len(&mut env, 1024)?;
+///     let last_frame = Buffer::<f32>::with_len(&mut env, 1024)?;
+///     let scene = vec![1.0f32; 1024];
+///     let pixels = vec![0.0f32; 1024];
+///
+///     // upload the next frame while reading back the last one
+///     let upload = next_frame.to_async(scene, &mut env, &[])?;
+///     let readback = last_frame.from_async(pixels, &mut env, &[])?;
+///     let scene = upload.wait()?;
+///     let pixels = readback.wait()?; // readback gives pixels back, filled in
+///     println!("{} {}", scene[0], pixels[0]);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+#[must_use = "dropping a Pending waits for it straight away"]
+pub struct Pending<T> {
+    event: Event,
+    // None once it's been given back
+    data: Option<Vec<T>>,
+}
+
+impl<T> Pending<T> {
+    pub(crate) fn new(event: Event, data: Vec<T>) -> Self {
+        Pending {
+            event,
+            data: Some(data),
+        }
+    }
+
+    /// Block until the transfer is done, giving the memory back.
+    pub fn wait(mut self) -> Result<Vec<T>, ClError> {
+        self.event.wait()?;
+        Ok(self.data.take().unwrap_or_default())
+    }
+
+    /// The transfer's Event, to make other commands wait for it.
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    /// True once the transfer is done. The memory is still the Pending's until it is
+    /// waited on.
+    pub fn is_complete(&self) -> Result<bool, ClError> {
+        self.event.is_complete()
+    }
+}
+
+/// Awaiting a Pending gives the memory back once the transfer is done, without blocking.
+/// A Pending that's dropped before it's done, like when its task is cancelled, still
+/// blocks until the transfer finishes.
+// nothing in a Pending is ever pinned; the Vec is only moved out whole
+impl<T> Unpin for Pending<T> {}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<Vec<T>, ClError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.event).poll(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.data.take().unwrap_or_default())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Pending<T> {
+    fn drop(&mut self) {
+        // errors were the caller's to get from wait(); here the device just has to be done
+        // with the memory before it's freed
+        if self.data.is_some() {
+            let _ = self.event.wait();
+        }
+    }
+}
+
+/// EventTimes is when a command went through each stage, in nanoseconds on the device's
+/// clock. Only commands on a profiling Env have them; see Env::use_profiling().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventTimes {
+    pub queued: u64,
+    pub submitted: u64,
+    pub started: u64,
+    pub ended: u64,
+}
+
+impl EventTimes {
+    /// How long the command ran on the device.
+    pub fn duration(&self) -> Duration {
+        Duration::from_nanos(self.ended.saturating_sub(self.started))
+    }
+    /// How long the command waited between being queued and starting.
+    pub fn latency(&self) -> Duration {
+        Duration::from_nanos(self.started.saturating_sub(self.queued))
+    }
+}
+
+/// WaitList is a list of Events in the form the enqueue calls want them.
+pub(crate) struct WaitList(Vec<cl_event>);
+
+impl WaitList {
+    pub(crate) fn new(events: &[&Event]) -> Self {
+        WaitList(events.iter().map(|e| e.event).collect())
+    }
+    pub(crate) fn len(&self) -> cl_uint {
+        self.0.len() as cl_uint
+    }
+    /// OpenCL wants null, not just any pointer, when the list is empty.
+    pub(crate) fn as_ptr(&self) -> *const cl_event {
+        if self.0.is_empty() {
+            std::ptr::null()
+        } else {
+            self.0.as_ptr()
+        }
+    }
+}
+
+impl Clone for Event {
+    fn clone(&self) -> Self {
+        unsafe {
+            clRetainEvent(self.event);
+        }
+        Event::new(self.event)
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe {
+            clReleaseEvent(self.event);
+        }
+    }
+}
@@ -1,14 +1,222 @@
 use crate::data::Buffer;
-use crate::runtime::{ClError, Env};
+use crate::error::check;
+use crate::event::{Event, WaitList};
+use crate::profile::Command;
+use crate::runtime::{ClError, Env, device_string, work_item_sizes};
 use obwio::*;
 use std::ffi::c_void;
 
+/// Kernel is a handle to one kernel of a program. It's returned by Program::make_kernel(),
+/// and released when dropped.
+/// Arguments belong to the kernel they were set on, so several kernels from the same
+/// program can be set up once and then run one after another.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::data::Buffer;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let program = env
+///         .use_kernel("examples/vecadd_kernel.cl")?
+///         .program(&BuildOptions::new())?;
+///     let mut first = program.make_kernel("vec_add")?;
+///     let mut second = program.make_kernel("vec_add")?;
+///
+///     let a = vec![1.0f32; 4];
+///     let buf_a = Buffer::new(&mut env, &a)?;
+///     let buf_b = Buffer::new(&mut env, &a)?;
+///     let buf_c = Buffer::new(&mut env, &a)?;
+///
+///     // c = a + b
+///     first.setarg(&buf_a, 0)?;
+///     first.setarg(&buf_b, 1)?;
+///     first.setarg(&buf_c, 2)?;
+///     // b = c + a
+///     second.setarg(&buf_c, 0)?;
+///     second.setarg(&buf_a, 1)?;
+///     second.setarg(&buf_b, 2)?;
+///
+///     first.run(&mut env, a.len())?;
+///     second.run(&mut env, a.len())?;
+///     Ok(())
+/// }
+/// ```
+pub struct Kernel {
+    pub kernel: cl_kernel,
+    pub name: String,
+}
+
+impl Kernel {
+    /// Set a buffer argument. See setarg().
+    pub fn setarg<T>(&mut self, buffer: &Buffer<T>, arg: usize) -> Result<(), ClError>
+    where
+        T: Clone + 'static,
+    {
+        setarg(self, buffer, arg)
+    }
+    /// Set a scalar argument. See setarg_scalar().
+    pub fn setarg_scalar<T>(&mut self, val: &T, arg: usize) -> Result<(), ClError>
+    where
+        T: Clone + 'static,
+    {
+        setarg_scalar(self, val, arg)
+    }
+    /// Run the kernel and wait for it. See run_kernel().
+    pub fn run(&self, env: &mut Env, range: impl Into<NdRange>) -> Result<(), ClError> {
+        run_kernel(env, self, range)
+    }
+    /// Start the kernel once the events in after are done, without waiting for it.
+    /// See enqueue_kernel().
+    pub fn enqueue(
+        &self,
+        env: &mut Env,
+        range: impl Into<NdRange>,
+        after: &[&Event],
+    ) -> Result<Event, ClError> {
+        enqueue_kernel(env, self, range, after)
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        unsafe {
+            clReleaseKernel(self.kernel);
+        }
+    }
+}
+
+/// NdRange says how many work-items a kernel is run with, in 1, 2 or 3 dimensions.
+/// Optionally it also sets the local work-group size, and an offset added to every global id.
+/// A plain usize, or a tuple of two or three, turns into an NdRange, so most launches
+/// don't need to build one by hand.
+///
+/// # Examples
+///
+/// ```rust
+/// use obrah::kernel::NdRange;
+///
+/// let range = NdRange::d2(1920, 1088).local([16, 16]).offset([0, 8]);
+/// assert_eq!(range.dims(), 2);
+/// assert_eq!(range.global(), &[1920, 1088]);
+/// assert_eq!(range.local_size(), Some(&[16, 16][..]));
+///
+/// let range: NdRange = 1024.into();
+/// assert_eq!(range, NdRange::d1(1024));
+/// let range: NdRange = (640, 480).into();
+/// assert_eq!(range, NdRange::d2(640, 480));
+/// ```
+///
+/// Local sizes and offsets have at most 3 dimensions:
+///
+/// ```rust,compile_fail
+/// use obrah::kernel::NdRange;
+///
+/// let range = NdRange::d1(64).local([8, 1, 1, 1]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NdRange {
+    dims: usize,
+    global: [usize; 3],
+    local: Option<([usize; 3], usize)>,
+    offset: Option<([usize; 3], usize)>,
+}
+
+impl NdRange {
+    /// A 1-D range of x work-items.
+    pub fn d1(x: usize) -> Self {
+        Self::new(1, [x, 1, 1])
+    }
+    /// A 2-D range of x by y work-items.
+    pub fn d2(x: usize, y: usize) -> Self {
+        Self::new(2, [x, y, 1])
+    }
+    /// A 3-D range of x by y by z work-items.
+    pub fn d3(x: usize, y: usize, z: usize) -> Self {
+        Self::new(3, [x, y, z])
+    }
+
+    fn new(dims: usize, global: [usize; 3]) -> Self {
+        NdRange {
+            dims,
+            global,
+            local: None,
+            offset: None,
+        }
+    }
+
+    /// Set the local work-group size. It needs as many dimensions as the range. Before
+    /// OpenCL C 2.0 it also has to divide the global size in each; from 2.0 on, only if the
+    /// program isn't built for CL2.0 or later. Without it, the driver picks one.
+    /// More than 3 dimensions doesn't compile.
+    pub fn local<const N: usize>(mut self, local: [usize; N]) -> Self {
+        const { assert!(N <= 3, "a local size has at most 3 dimensions") };
+        self.local = Some((pad(&local), N));
+        self
+    }
+
+    /// Set the global offset. It needs as many dimensions as the range.
+    /// More than 3 dimensions doesn't compile.
+    pub fn offset<const N: usize>(mut self, offset: [usize; N]) -> Self {
+        const { assert!(N <= 3, "an offset has at most 3 dimensions") };
+        self.offset = Some((pad(&offset), N));
+        self
+    }
+
+    /// The number of dimensions.
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+    /// The global size, one number per dimension.
+    pub fn global(&self) -> &[usize] {
+        &self.global[..self.dims]
+    }
+    /// The local work-group size, if one was set.
+    pub fn local_size(&self) -> Option<&[usize]> {
+        self.local.as_ref().map(|(local, n)| &local[..*n])
+    }
+    /// The global offset, if one was set.
+    pub fn global_offset(&self) -> Option<&[usize]> {
+        self.offset.as_ref().map(|(offset, n)| &offset[..*n])
+    }
+}
+
+fn pad(values: &[usize]) -> [usize; 3] {
+    let mut padded = [1; 3];
+    for (p, v) in padded.iter_mut().zip(values) {
+        *p = *v;
+    }
+    padded
+}
+
+impl From<usize> for NdRange {
+    fn from(x: usize) -> Self {
+        NdRange::d1(x)
+    }
+}
+
+impl From<(usize, usize)> for NdRange {
+    fn from((x, y): (usize, usize)) -> Self {
+        NdRange::d2(x, y)
+    }
+}
+
+impl From<(usize, usize, usize)> for NdRange {
+    fn from((x, y, z): (usize, usize, usize)) -> Self {
+        NdRange::d3(x, y, z)
+    }
+}
+
 /// setarg() sets an argument. For scalar values, use setarg_scalar().
 /// The third parameter, arg, is the 0-based index of the kernel argument.
 ///
 /// # Examples
 ///
-/// ```rust
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
 /// use obrah::kernel;
 /// use obrah::runtime;
 /// use obrah::data;
@@ -17,87 +225,45 @@ use std::ffi::c_void;
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
 ///
 ///     let mut env = Env::new(0, 0)?;
-///     env.use_kernel("examples/vecadd_kernel.cl")?.program()?.make_kernel("vec_add")?;
+///     let mut kern = env
+///         .use_kernel("examples/vecadd_kernel.cl")?
+///         .program(&BuildOptions::new())?
+///         .make_kernel("vec_add")?;
 ///
-///     let mut a = vec![7.0f32, 8.0, 2.0, 6.0];
-///     let mut b = vec![134.0f32, 134.11, 34.8, 112.9];
-///     let mut result = vec![0.0f32; a.len()];
+///     let a = vec![7.0f32, 8.0, 2.0, 6.0];
+///     let b = vec![134.0f32, 134.11, 34.8, 112.9];
+///     let result = vec![0.0f32; a.len()];
 ///
 ///     // Buffers
-///     let mut buf_a = data::Buffer::new(&mut env, &mut a);
-///     let mut buf_b = data::Buffer::new(&mut env, &mut b);
-///     let mut buf_result = data::Buffer::new(&mut env, &mut result);
+///     let mut buf_a = data::Buffer::new(&mut env, &a)?;
+///     let mut buf_b = data::Buffer::new(&mut env, &b)?;
+///     let mut buf_result = data::Buffer::new(&mut env, &result)?;
 ///
 ///      // Send data to GPU
-///     buf_a.to(&mut env);
-///     buf_b.to(&mut env);
+///     buf_a.to(&a, &mut env)?;
+///     buf_b.to(&b, &mut env)?;
 ///
 ///     // Set kernel arguments
-///     kernel::setarg(&env, &mut buf_a, 0);
-///     kernel::setarg(&env, &mut buf_b, 1);
-///     kernel::setarg(&env, &mut buf_result, 2);
+///     kernel::setarg(&mut kern, &buf_a, 0)?;
+///     kernel::setarg(&mut kern, &buf_b, 1)?;
+///     kernel::setarg(&mut kern, &buf_result, 2)?;
 /// Ok(())
 /// }
 /// ```
 ///
-pub fn setarg<T>(env: &Env, buffer: &Buffer<T>, arg: usize) -> Result<(), ClError>
+pub fn setarg<T>(kernel: &mut Kernel, buffer: &Buffer<T>, arg: usize) -> Result<(), ClError>
 where
     T: Clone + 'static,
 {
     unsafe {
         let size = std::mem::size_of::<cl_mem>();
         let buf_ptr: *const std::ffi::c_void = &buffer.buffer as *const _ as *const _;
-        clSetKernelArg(env.kernel, arg as u32, size, buf_ptr as *const _);
-        if env.err != 0 {
-            return Err(ClError::from(env.err));
-        }
-        Ok(())
-    }
-}
-
-pub fn get_devices() {
-    unsafe {
-        let mut num_platforms = 0;
-        clGetPlatformIDs(0, std::ptr::null_mut(), &mut num_platforms);
-        let mut platforms = vec![std::ptr::null_mut(); num_platforms as usize];
-        clGetPlatformIDs(num_platforms, platforms.as_mut_ptr(), std::ptr::null_mut());
-
-        for (p_idx, &platform) in platforms.iter().enumerate() {
-            println!("Platform {}:", p_idx);
-
-            let mut num_devices = 0;
-            clGetDeviceIDs(
-                platform,
-                CL_DEVICE_TYPE_ALL.into(),
-                0,
-                std::ptr::null_mut(),
-                &mut num_devices,
-            );
-            let mut devices = vec![std::ptr::null_mut(); num_devices as usize];
-            clGetDeviceIDs(
-                platform,
-                CL_DEVICE_TYPE_ALL.into(),
-                num_devices,
-                devices.as_mut_ptr(),
-                std::ptr::null_mut(),
-            );
-
-            for (d_idx, &device) in devices.iter().enumerate() {
-                let mut size = 0;
-                clGetDeviceInfo(device, CL_DEVICE_NAME, 0, std::ptr::null_mut(), &mut size);
-                let mut name_buf = vec![0u8; size];
-                clGetDeviceInfo(
-                    device,
-                    CL_DEVICE_NAME,
-                    size,
-                    name_buf.as_mut_ptr() as *mut _,
-                    std::ptr::null_mut(),
-                );
-
-                let name = std::ffi::CStr::from_bytes_with_nul(&name_buf).unwrap();
-                println!("  Device {}: {}", d_idx, name.to_str().unwrap());
-            }
-        }
+        let err = clSetKernelArg(kernel.kernel, arg as u32, size, buf_ptr as *const _);
+        check("clSetKernelArg", err).map_err(|e| {
+            e.with_kernel(&kernel.name)
+                .with_arg(arg)
+                .with_size(buffer.len() * std::mem::size_of::<T>())
+        })
     }
 }
 
@@ -105,39 +271,150 @@ pub fn get_devices() {
 /// Scalar arguments are single-data types, such as
 /// floats and integers.
 /// They cannot be read from.
-pub fn setarg_scalar<T>(env: &Env, val: &T, arg: usize)
+pub fn setarg_scalar<T>(kernel: &mut Kernel, val: &T, arg: usize) -> Result<(), ClError>
 where
     T: Clone + 'static,
 {
     unsafe {
         let size = std::mem::size_of::<T>();
-        clSetKernelArg(
-            env.kernel,
+        let err = clSetKernelArg(
+            kernel.kernel,
             arg as u32,
             size,
             val as *const T as *const c_void,
         );
-        if env.err != 0 {
-            panic!("OpenCL error: {}", env.err);
-        }
+        check("clSetKernelArg", err)
+            .map_err(|e| e.with_kernel(&kernel.name).with_arg(arg).with_size(size))
     }
 }
 
-/// Run the kernel! Simply input the number of threads in the x, and threads in the y.
-pub fn run_kernel(env: &mut Env, threadsx: usize, threadsy: usize) {
+/// Run the kernel! Simply input the number of threads; a usize for 1-D kernels, (x, y) for 2-D
+/// and (x, y, z) for 3-D, or an NdRange to set the local size and offset too.
+/// The local size is checked against what the kernel and device can do before launching.
+/// This waits for the kernel to finish. To carry on while it runs, use enqueue_kernel().
+pub fn run_kernel(
+    env: &mut Env,
+    kernel: &Kernel,
+    range: impl Into<NdRange>,
+) -> Result<(), ClError> {
+    enqueue_kernel(env, kernel, range, &[])?
+        .wait()
+        .map_err(|e| e.with_kernel(&kernel.name))
+}
+
+/// Start the kernel, and give back straight away. The returned Event says when it's done.
+/// Takes the same ranges as run_kernel(). The kernel doesn't start until every event in
+/// after is done, e.g. the writes of its inputs; pass &[] to start it right away.
+pub fn enqueue_kernel(
+    env: &mut Env,
+    kernel: &Kernel,
+    range: impl Into<NdRange>,
+    after: &[&Event],
+) -> Result<Event, ClError> {
+    let range = range.into();
+    check_range(env, kernel, &range)?;
+    let after = WaitList::new(after);
     unsafe {
-        let global_work_size: [usize; 2] = [threadsx, threadsy];
-        clEnqueueNDRangeKernel(
+        let mut event = std::ptr::null_mut();
+        let err = clEnqueueNDRangeKernel(
             env.queue,
-            env.kernel,
-            2,
-            std::ptr::null(),
-            global_work_size.as_ptr(),
-            std::ptr::null(),
-            0,
-            std::ptr::null_mut(),
+            kernel.kernel,
+            range.dims as cl_uint,
+            range
+                .offset
+                .as_ref()
+                .map_or(std::ptr::null(), |(offset, _)| offset.as_ptr()),
+            range.global.as_ptr(),
+            range
+                .local
+                .as_ref()
+                .map_or(std::ptr::null(), |(local, _)| local.as_ptr()),
+            after.len(),
+            after.as_ptr(),
+            &mut event,
+        );
+        check("clEnqueueNDRangeKernel", err).map_err(|e| e.with_kernel(&kernel.name))?;
+        // make sure the driver actually starts on it, rather than waiting for more work
+        check("clFlush", clFlush(env.queue))?;
+        let event = Event::new(event);
+        env.record(Command::Kernel(kernel.name.clone()), &event);
+        Ok(event)
+    }
+}
+
+/// opencl_c_major() gets the newest OpenCL C major version a device compiles,
+/// from a CL_DEVICE_OPENCL_C_VERSION like "OpenCL C 1.2 ...".
+fn opencl_c_major(device: cl_device_id) -> Option<u32> {
+    let version = device_string(device, CL_DEVICE_OPENCL_C_VERSION);
+    let rest = version.strip_prefix("OpenCL C ")?;
+    rest.split('.').next()?.parse().ok()
+}
+
+/// check_range() makes sure a range can be launched, so mistakes come back as clear errors
+/// instead of whatever the driver makes of them.
+fn check_range(env: &Env, kernel: &Kernel, range: &NdRange) -> Result<(), ClError> {
+    if let Some(dim) = range.global().iter().position(|&n| n == 0) {
+        return Err(ClError::EmptyRange { dim });
+    }
+    if let Some((_, found)) = range.offset
+        && found != range.dims
+    {
+        return Err(ClError::RangeDimensions {
+            expected: range.dims,
+            found,
+        });
+    }
+    let Some(local) = range.local_size() else {
+        return Ok(());
+    };
+    if local.len() != range.dims {
+        return Err(ClError::RangeDimensions {
+            expected: range.dims,
+            found: local.len(),
+        });
+    }
+    if let Some(dim) = local.iter().position(|&n| n == 0) {
+        return Err(ClError::EmptyWorkGroup { dim });
+    }
+    // OpenCL C before 2.0 only runs whole work-groups. Newer devices can run a partial one
+    // at the edge if the program was built for CL2.0 or later, so that's the driver's call.
+    if opencl_c_major(env.device).is_some_and(|major| major < 2) {
+        for (dim, (&global, &local)) in range.global().iter().zip(local).enumerate() {
+            if global % local != 0 {
+                return Err(ClError::UnevenWorkGroup { dim, global, local });
+            }
+        }
+    }
+
+    unsafe {
+        let mut max_group: usize = 0;
+        let err = clGetKernelWorkGroupInfo(
+            kernel.kernel,
+            env.device,
+            CL_KERNEL_WORK_GROUP_SIZE,
+            std::mem::size_of::<usize>(),
+            &mut max_group as *mut usize as *mut _,
             std::ptr::null_mut(),
         );
-        clFinish(env.queue);
+        check("clGetKernelWorkGroupInfo", err).map_err(|e| e.with_kernel(&kernel.name))?;
+        // a product too big for usize is certainly too big for the device
+        let size = local
+            .iter()
+            .try_fold(1usize, |size, &n| size.checked_mul(n))
+            .unwrap_or(usize::MAX);
+        if size > max_group {
+            return Err(ClError::WorkGroupTooLarge {
+                size,
+                max: max_group,
+            });
+        }
+
+        let max_items = work_item_sizes(env.device)?;
+        for (dim, (&size, &max)) in local.iter().zip(&max_items).enumerate() {
+            if size > max {
+                return Err(ClError::WorkItemTooLarge { dim, size, max });
+            }
+        }
     }
+    Ok(())
 }
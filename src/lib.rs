@@ -3,17 +3,52 @@
 /// GPGPU computing, meaning anyone can do it.
 /// 
 /// ## Modules
-/// OBRAH has 3 modules:
+/// OBRAH has 11 modules:
 /// - kernel
 /// - data
+/// - device
 /// - runtime
-/// These three libraries are necessary in every OBRAH program.
+/// - program
+/// - build
+/// - cache
+/// - error
+/// - event
+/// - profile
+/// - split
+///
+/// The first three are necessary in every OBRAH program.
 /// ### Kernel:
 /// The Kernel module provides functions for building kernels and running them.
 /// ### Data:
 /// The Data module allows for sending data to and from the GPU, and making buffers for it.
 /// ### Runtime:
 /// The Runtime module provides all the main functions for OBRAH; setup, cleanup, and program making.
+/// ### Program:
+/// The Program module builds OpenCL programs from source, and makes kernels from them.
+/// ### Build:
+/// The Build module holds build options and build logs, and parses the compiler's errors and
+/// warnings out of them.
+/// ### Cache:
+/// The Cache module keeps built programs on disk, so they don't have to be compiled every run.
+/// ### Error:
+/// The Error module holds ClError, with every OpenCL status code in it. It's also in runtime.
+/// ### Event:
+/// The Event module holds Event, for waiting on commands that were started without blocking.
+/// ### Device:
+/// The Device module lists platforms and devices and what they can do, and picks one with
+/// DeviceSelector.
+/// ### Profile:
+/// The Profile module adds up how long kernels and transfers take on the GPU.
+/// ### Split:
+/// The Split module spreads one launch over several devices, and gathers the output.
 pub mod runtime;
 pub mod data;
-pub mod kernel;
\ No newline at end of file
+pub mod device;
+pub mod kernel;
+pub mod program;
+pub mod build;
+pub mod cache;
+pub mod error;
+pub mod event;
+pub mod profile;
+pub mod split;
\ No newline at end of file
@@ -0,0 +1,252 @@
+use crate::event::Event;
+use crate::runtime::ClError;
+use std::cmp::Reverse;
+use std::fmt;
+use std::time::Duration;
+
+/// Profiler keeps every command run on a profiling Env, so their GPU times can be added up.
+/// Turn it on with Env::use_profiling(), and get the totals with Env::profile_report().
+///
+/// Each command's Event is kept until clear() is called, so call it now and then in
+/// long-running programs.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::data::Buffer;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let mut kernel = env
+///         .use_profiling()?
+///         .use_kernel("examples/vecadd_kernel.cl")?
+///         .program(&BuildOptions::new())?
+///         .make_kernel("vec_add")?;
+///
+///     let a = vec![1.0f32; 1 << 20];
+///     let mut buf_a = Buffer::new(&mut env, &a)?;
+///     buf_a.to(&a, &mut env)?;
+///     // ... set the arguments and run the kernel ...
+///
+///     println!("{}", env.profile_report()?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Profiler {
+    records: Vec<(Command, Event)>,
+}
+
+/// What a recorded Event was for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    Kernel(String),
+    Transfer(Direction, usize),
+}
+
+/// Which way a transfer went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host to the device.
+    Write,
+    /// From the device to the host.
+    Read,
+    /// On the device, from one buffer to another.
+    Copy,
+    /// On the device, setting a buffer to one value.
+    Fill,
+}
+
+impl Profiler {
+    pub(crate) fn record(&mut self, command: Command, event: &Event) {
+        self.records.push((command, event.clone()));
+    }
+
+    /// The number of commands recorded so far.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Forget everything recorded so far.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Add up the times of everything recorded. This waits for any command still running.
+    pub fn report(&self) -> Result<ProfileReport, ClError> {
+        let mut report = ProfileReport::default();
+        for (command, event) in &self.records {
+            event.wait()?;
+            let time = event.times()?.duration();
+            match command {
+                Command::Kernel(name) => {
+                    let stats = match report.kernels.iter_mut().find(|k| &k.name == name) {
+                        Some(stats) => stats,
+                        None => {
+                            report.kernels.push(KernelStats {
+                                name: name.clone(),
+                                calls: 0,
+                                total: Duration::ZERO,
+                            });
+                            report.kernels.last_mut().unwrap()
+                        }
+                    };
+                    stats.calls += 1;
+                    stats.total += time;
+                }
+                Command::Transfer(direction, bytes) => {
+                    let stats = match report
+                        .transfers
+                        .iter_mut()
+                        .find(|t| t.direction == *direction)
+                    {
+                        Some(stats) => stats,
+                        None => {
+                            report.transfers.push(TransferStats {
+                                direction: *direction,
+                                calls: 0,
+                                bytes: 0,
+                                total: Duration::ZERO,
+                            });
+                            report.transfers.last_mut().unwrap()
+                        }
+                    };
+                    stats.calls += 1;
+                    stats.bytes += bytes;
+                    stats.total += time;
+                }
+            }
+        }
+        report.kernels.sort_by_key(|k| Reverse(k.total));
+        Ok(report)
+    }
+}
+
+/// ProfileReport is the GPU time spent on each kernel and on transfers, from
+/// Env::profile_report(). Kernels are sorted with the slowest in total first.
+/// Its Display is a table, ready to print.
+///
+/// # Examples
+///
+/// ```rust
+/// use obrah::profile::{KernelStats, ProfileReport};
+/// use std::time::Duration;
+///
+/// let report = ProfileReport {
+///     kernels: vec![KernelStats {
+///         name: "raytrace".into(),
+///         calls: 180,
+///         total: Duration::from_millis(2700),
+///     }],
+///     transfers: vec![],
+/// };
+/// let table = report.to_string();
+/// assert!(table.contains("raytrace"));
+/// assert!(table.contains("15.000ms")); // the mean
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProfileReport {
+    pub kernels: Vec<KernelStats>,
+    pub transfers: Vec<TransferStats>,
+}
+
+/// The time spent in one kernel, over every time it was run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelStats {
+    pub name: String,
+    pub calls: usize,
+    pub total: Duration,
+}
+
+impl KernelStats {
+    /// The time a call took on average.
+    pub fn mean(&self) -> Duration {
+        mean(self.total, self.calls)
+    }
+}
+
+/// The time spent moving data one way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferStats {
+    pub direction: Direction,
+    pub calls: usize,
+    pub bytes: usize,
+    pub total: Duration,
+}
+
+impl TransferStats {
+    /// The time a transfer took on average.
+    pub fn mean(&self) -> Duration {
+        mean(self.total, self.calls)
+    }
+    /// Bytes moved per second, while transfers were running.
+    pub fn bandwidth(&self) -> f64 {
+        if self.total.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.total.as_secs_f64()
+    }
+}
+
+fn mean(total: Duration, calls: usize) -> Duration {
+    if calls == 0 {
+        return Duration::ZERO;
+    }
+    total / calls as u32
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>12} {:>12}",
+            "kernel", "calls", "total", "mean"
+        )?;
+        for k in &self.kernels {
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>12} {:>12}",
+                k.name,
+                k.calls,
+                millis(k.total),
+                millis(k.mean())
+            )?;
+        }
+        if self.transfers.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "\n{:<24} {:>8} {:>12} {:>12} {:>12}",
+            "transfer", "calls", "total", "mean", "bandwidth"
+        )?;
+        for t in &self.transfers {
+            let direction = match t.direction {
+                Direction::Write => "write (host to device)",
+                Direction::Read => "read (device to host)",
+                Direction::Copy => "copy (device to device)",
+                Direction::Fill => "fill (on the device)",
+            };
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>12} {:>12} {:>9.2}GB/s",
+                direction,
+                t.calls,
+                millis(t.total),
+                millis(t.mean()),
+                t.bandwidth() / 1e9
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn millis(time: Duration) -> String {
+    format!("{:.3}ms", time.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,446 @@
+use crate::build::{BuildLog, BuildOptions, DeviceLog};
+use crate::cache::KernelCache;
+use crate::kernel::Kernel;
+use crate::runtime::{ClError, Env, device_name, device_string, info_string};
+use obwio::*;
+use std::ffi::CString;
+
+/// Program is a built OpenCL program. It's made from one or more sources against the
+/// context of an Env, and you can have as many of them side by side as you want.
+/// The program is released when dropped. Kernels made from it keep working after that,
+/// as OpenCL keeps the program alive for them.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::program::Program;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let env = Env::new(0, 0)?;
+///     let options = BuildOptions::new();
+///     let add = Program::new(&env, &["__kernel void add(__global float* a) { a[0] += 1.0f; }"], &options)?;
+///     let sub = Program::new(&env, &["__kernel void sub(__global float* a) { a[0] -= 1.0f; }"], &options)?;
+///
+///     let add_kernel = add.make_kernel("add")?;
+///     let sub_kernel = sub.make_kernel("sub")?;
+///     Ok(())
+/// }
+/// ```
+pub struct Program {
+    pub program: cl_program,
+    /// The build log, if the compiler printed anything. This is where build warnings end up.
+    pub build_log: Option<BuildLog>,
+}
+
+impl Program {
+    /// Build a program from one or more sources. The sources are joined together, in order,
+    /// like OpenCL does with clCreateProgramWithSource.
+    /// If the Env has a cache, the binary is loaded from there when it can be, unless the
+    /// options have include directories.
+    pub fn new(env: &Env, sources: &[&str], options: &BuildOptions) -> Result<Program, ClError> {
+        make_prog(env, sources, options)
+    }
+
+    /// Build a program from a single source string.
+    pub fn from_source(
+        env: &Env,
+        source: &str,
+        options: &BuildOptions,
+    ) -> Result<Program, ClError> {
+        make_prog(env, &[source], options)
+    }
+
+    /// Compile every source on its own, then link them together into one program.
+    /// Unlike new(), each source is its own compilation unit, so they can each have their own
+    /// helpers and their build logs point at their own lines. Kernels can call functions from
+    /// another source, as long as they declare them first.
+    /// Every source is compiled with all of the options, only the math flags are used for linking.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use obrah::build::BuildOptions;
+    /// use obrah::program::Program;
+    /// use obrah::runtime::Env;
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let env = Env::new(0, 0)?;
+    ///     let helpers = "float twice(float x) { return x * 2.0f; }";
+    ///     let kernels = "float twice(float x);
+    ///                    __kernel void double_it(__global float* a) { a[0] = twice(a[0]); }";
+    ///     let program = Program::link(&env, &[helpers, kernels], &BuildOptions::new())?;
+    ///     let kernel = program.make_kernel("double_it")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn link(env: &Env, sources: &[&str], options: &BuildOptions) -> Result<Program, ClError> {
+        link_prog(env, sources, options)
+    }
+
+    /// Make a kernel from the program. Every call gives back a new Kernel, so you can
+    /// make as many as you like from the same program.
+    pub fn make_kernel(&self, name: &str) -> Result<Kernel, ClError> {
+        unsafe {
+            let cname = CString::new(name).map_err(|_| ClError::InvalidKernelName)?;
+            let mut err = 0;
+            let kernel = clCreateKernel(self.program, cname.as_ptr(), &mut err);
+            if kernel.is_null() {
+                Err(ClError::from_call("clCreateKernel", err).with_kernel(name))
+            } else {
+                Ok(Kernel {
+                    kernel,
+                    name: name.to_string(),
+                })
+            }
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe {
+            clReleaseProgram(self.program);
+        }
+    }
+}
+
+// The make_prog() function builds the sources into an actual OpenCL program,
+// going through the Env's cache if it has one. Builds that #include from directories skip
+// the cache, since the key only covers the source and options, not the headers.
+fn make_prog(env: &Env, sources: &[&str], options: &BuildOptions) -> Result<Program, ClError> {
+    let includes = options.has_include_dirs();
+    let options = options.to_string();
+    let Some(cache) = env.cache.as_ref().filter(|_| !includes) else {
+        return source_prog(env, sources, &options);
+    };
+
+    // every device gets its own binary, so each has its own key
+    let source = sources.concat();
+    let keys: Vec<String> = env
+        .devices
+        .iter()
+        .map(|&device| {
+            KernelCache::key(
+                &source,
+                &options,
+                &device_name(device),
+                &device_string(device, CL_DRIVER_VERSION),
+            )
+        })
+        .collect();
+    let binaries: Option<Vec<Vec<u8>>> = keys.iter().map(|key| cache.load(key)).collect();
+    if let Some(binaries) = binaries
+        && let Some(program) = binary_prog(env, &binaries, &source, &options)
+    {
+        return Ok(program);
+    }
+
+    let program = source_prog(env, sources, &options)?;
+    if let Some(binaries) = program_binaries(&program, env.devices.len()) {
+        for (key, binary) in keys.iter().zip(&binaries) {
+            // not being able to write the cache only makes the next startup slower
+            let _ = cache.store(key, binary);
+        }
+    }
+    Ok(program)
+}
+
+// The source_prog() function compiles the sources from scratch.
+fn source_prog(env: &Env, sources: &[&str], options: &str) -> Result<Program, ClError> {
+    unsafe {
+        let c_options = CString::new(options).map_err(|_| ClError::InteriorNul)?;
+        let c_sources = sources
+            .iter()
+            .map(|s| CString::new(*s).map_err(|_| ClError::InteriorNul))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut src_ptrs: Vec<_> = c_sources.iter().map(|s| s.as_ptr()).collect();
+
+        let mut err = 0;
+        let program = clCreateProgramWithSource(
+            env.context,
+            src_ptrs.len() as cl_uint,
+            src_ptrs.as_mut_ptr(),
+            std::ptr::null(),
+            &mut err,
+        );
+        if program.is_null() {
+            return Err(ClError::from_call("clCreateProgramWithSource", err));
+        }
+
+        let builderr = clBuildProgram(
+            program,
+            env.devices.len() as cl_uint,
+            env.devices.as_ptr(),
+            c_options.as_ptr(),
+            None,
+            std::ptr::null_mut(),
+        );
+        let log = build_log(program, &env.devices, &sources.concat(), options);
+        if builderr == CL_BUILD_PROGRAM_FAILURE {
+            clReleaseProgram(program);
+            Err(ClError::BuildFailed(log))
+        } else if builderr != 0 {
+            clReleaseProgram(program);
+            Err(ClError::from_call("clBuildProgram", builderr))
+        } else {
+            Ok(Program {
+                program,
+                build_log: if log.is_empty() { None } else { Some(log) },
+            })
+        }
+    }
+}
+
+// The binary_prog() function makes a program from cached binaries, one per device. It gives
+// back None if the driver won't take them, like after a driver update it doesn't show in its
+// version.
+fn binary_prog(env: &Env, binaries: &[Vec<u8>], source: &str, options: &str) -> Option<Program> {
+    unsafe {
+        let c_options = CString::new(options).ok()?;
+        let lengths: Vec<usize> = binaries.iter().map(Vec::len).collect();
+        let mut binary_ptrs: Vec<*const u8> = binaries.iter().map(|b| b.as_ptr()).collect();
+        let mut status = vec![0; binaries.len()];
+        let mut err = 0;
+        let program = clCreateProgramWithBinary(
+            env.context,
+            env.devices.len() as cl_uint,
+            env.devices.as_ptr(),
+            lengths.as_ptr(),
+            binary_ptrs.as_mut_ptr(),
+            status.as_mut_ptr(),
+            &mut err,
+        );
+        if program.is_null() {
+            return None;
+        }
+        if err != 0 || status.iter().any(|&s| s != 0) {
+            clReleaseProgram(program);
+            return None;
+        }
+
+        // binaries still have to be built before kernels can be made from them
+        let builderr = clBuildProgram(
+            program,
+            env.devices.len() as cl_uint,
+            env.devices.as_ptr(),
+            c_options.as_ptr(),
+            None,
+            std::ptr::null_mut(),
+        );
+        if builderr != 0 {
+            clReleaseProgram(program);
+            return None;
+        }
+
+        let log = build_log(program, &env.devices, source, options);
+        Some(Program {
+            program,
+            build_log: if log.is_empty() { None } else { Some(log) },
+        })
+    }
+}
+
+/// program_binaries() gets the built binary of a program for each of its devices, for
+/// caching. They come in the order of the Env's devices, as that's the order the program
+/// was made with.
+fn program_binaries(program: &Program, devices: usize) -> Option<Vec<Vec<u8>>> {
+    unsafe {
+        let mut sizes = vec![0usize; devices];
+        let err = clGetProgramInfo(
+            program.program,
+            CL_PROGRAM_BINARY_SIZES,
+            std::mem::size_of_val(sizes.as_slice()),
+            sizes.as_mut_ptr() as *mut _,
+            std::ptr::null_mut(),
+        );
+        if err != 0 || sizes.contains(&0) {
+            return None;
+        }
+
+        let mut binaries: Vec<Vec<u8>> = sizes.iter().map(|&size| vec![0u8; size]).collect();
+        let mut binary_ptrs: Vec<*mut u8> = binaries.iter_mut().map(|b| b.as_mut_ptr()).collect();
+        let err = clGetProgramInfo(
+            program.program,
+            CL_PROGRAM_BINARIES,
+            std::mem::size_of_val(binary_ptrs.as_slice()),
+            binary_ptrs.as_mut_ptr() as *mut _,
+            std::ptr::null_mut(),
+        );
+        if err != 0 { None } else { Some(binaries) }
+    }
+}
+
+// The link_prog() function compiles every source on its own and links them into a program.
+fn link_prog(env: &Env, sources: &[&str], options: &BuildOptions) -> Result<Program, ClError> {
+    unsafe {
+        let compile_options = options.to_string();
+        let link_options = options.link_options();
+        let c_compile_options =
+            CString::new(compile_options.as_str()).map_err(|_| ClError::InteriorNul)?;
+        let c_link_options =
+            CString::new(link_options.as_str()).map_err(|_| ClError::InteriorNul)?;
+        let c_sources = sources
+            .iter()
+            .map(|s| CString::new(*s).map_err(|_| ClError::InteriorNul))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut objects = Vec::with_capacity(sources.len());
+        let release = |objects: &[cl_program]| {
+            for &object in objects {
+                clReleaseProgram(object);
+            }
+        };
+
+        for (source, c_source) in sources.iter().zip(&c_sources) {
+            let mut src_ptr = c_source.as_ptr();
+            let mut err = 0;
+            let object =
+                clCreateProgramWithSource(env.context, 1, &mut src_ptr, std::ptr::null(), &mut err);
+            if object.is_null() {
+                release(&objects);
+                return Err(ClError::from_call("clCreateProgramWithSource", err));
+            }
+            objects.push(object);
+
+            let compileerr = clCompileProgram(
+                object,
+                env.devices.len() as cl_uint,
+                env.devices.as_ptr(),
+                c_compile_options.as_ptr(),
+                0,
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                None,
+                std::ptr::null_mut(),
+            );
+            if compileerr == CL_COMPILE_PROGRAM_FAILURE {
+                let log = build_log(object, &env.devices, source, &compile_options);
+                release(&objects);
+                return Err(ClError::BuildFailed(log));
+            } else if compileerr != 0 {
+                release(&objects);
+                return Err(ClError::from_call("clCompileProgram", compileerr));
+            }
+        }
+
+        let mut err = 0;
+        let program = clLinkProgram(
+            env.context,
+            env.devices.len() as cl_uint,
+            env.devices.as_ptr(),
+            c_link_options.as_ptr(),
+            objects.len() as cl_uint,
+            objects.as_ptr(),
+            None,
+            std::ptr::null_mut(),
+            &mut err,
+        );
+        // the objects aren't needed once linking is done, the linked program stands alone
+        release(&objects);
+
+        // the linker has no source of its own, so its log can't point at lines
+        let log = if program.is_null() {
+            None
+        } else {
+            Some(build_log(program, &env.devices, "", &link_options))
+        };
+        if err == CL_LINK_PROGRAM_FAILURE {
+            if !program.is_null() {
+                clReleaseProgram(program);
+            }
+            match log {
+                Some(log) => Err(ClError::BuildFailed(log)),
+                None => Err(ClError::from_call("clLinkProgram", err)),
+            }
+        } else if err != 0 || program.is_null() {
+            if !program.is_null() {
+                clReleaseProgram(program);
+            }
+            Err(ClError::from_call("clLinkProgram", err))
+        } else {
+            Ok(Program {
+                program,
+                build_log: log.filter(|log| !log.is_empty()),
+            })
+        }
+    }
+}
+
+/// build_log() collects CL_PROGRAM_BUILD_LOG from every device the program was built for.
+fn build_log(
+    program: cl_program,
+    devices: &[cl_device_id],
+    source: &str,
+    options: &str,
+) -> BuildLog {
+    let devices = devices
+        .iter()
+        .map(|&device| {
+            let log = unsafe {
+                let mut size = 0;
+                clGetProgramBuildInfo(
+                    program,
+                    device,
+                    CL_PROGRAM_BUILD_LOG,
+                    0,
+                    std::ptr::null_mut(),
+                    &mut size,
+                );
+                let mut buf = vec![0u8; size];
+                clGetProgramBuildInfo(
+                    program,
+                    device,
+                    CL_PROGRAM_BUILD_LOG,
+                    size,
+                    buf.as_mut_ptr() as *mut _,
+                    std::ptr::null_mut(),
+                );
+                info_string(buf)
+            };
+            DeviceLog::new(device_name(device), log)
+        })
+        .collect();
+
+    BuildLog {
+        source: source.to_string(),
+        options: options.to_string(),
+        devices,
+    }
+}
+
+/// include_kernel!() embeds a kernel file into the binary at compile time, like include_str!(),
+/// so the .cl file doesn't have to ship next to the executable. The path is relative to the
+/// file the macro is called from.
+///
+/// With just a path, it gives back the source as a &'static str. With an Env first, it builds
+/// the files straight into a Program, joined together in order, with the default BuildOptions.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use obrah::include_kernel;
+/// use obrah::runtime::Env;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let env = Env::new(0, 0)?;
+///     let source: &str = include_kernel!("../examples/vecadd_kernel.cl");
+///
+///     let program = include_kernel!(&env, "../examples/vecadd_kernel.cl")?;
+///     let kernel = program.make_kernel("vec_add")?;
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! include_kernel {
+    ($path:literal $(,)?) => {
+        include_str!($path)
+    };
+    ($env:expr, $($path:literal),+ $(,)?) => {
+        $crate::program::Program::new(
+            $env,
+            &[$(include_str!($path)),+],
+            &$crate::build::BuildOptions::new(),
+        )
+    };
+}
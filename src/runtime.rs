@@ -1,123 +1,188 @@
+use crate::build::BuildOptions;
+use crate::cache::KernelCache;
+use crate::device::{Device, DeviceSelector};
+use crate::error::check;
+pub use crate::error::{CallError, ClError};
+use crate::event::Event;
+use crate::profile::{Command, ProfileReport, Profiler};
+use crate::program::Program;
 use obwio::*;
-use std::ffi::CString;
-use std::fmt;
 use std::fs;
+use std::path::Path;
 
 /// This structure holds all the data; the platform, the device, the variables, etc.
 ///
+/// An Env can span several devices of one platform, with from_devices(). Then each device
+/// gets its own queue, programs are built for all of them, and use_device() picks the one
+/// that launches and transfers go to. device and queue are always that device's.
+///
 /// # Examples
 ///
-/// ```rust
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::runtime::Env;
 ///
-/// fn main() -> Result((), ClError) {
-///     use obrah::runtime::{ClError, Env};
-///     let mut env = Env::new(0, 0);
-///     env.use_kernel("examples/vecadd_kernel.cl").program()?;
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut env = Env::new(0, 0)?;
+///     let program = env
+///         .use_kernel("examples/vecadd_kernel.cl")?
+///         .program(&BuildOptions::new())?;
+///     let kernel = program.make_kernel("vec_add")?;
+///     Ok(())
 /// }
-/// ``````
+/// ```
 pub struct Env {
     pub platform: cl_platform_id,
+    /// The device in use. See use_device().
     pub device: cl_device_id,
     pub context: cl_context,
+    /// The queue of the device in use.
     pub queue: cl_command_queue,
-    pub program: cl_program,
-    pub kernel: cl_kernel,
+    /// Every device in the context.
+    pub devices: Vec<cl_device_id>,
+    /// One queue per device, in the same order as devices.
+    pub queues: Vec<cl_command_queue>,
     pub kerncode: Option<String>,
-    pub err: cl_int,
-}
-
-#[derive(Debug)]
-pub enum ClError {
-    InvalidProgram,
-    InvalidKernelName,
-    InvalidArgIndex,
-    OutOfResources,
-    OutOfMemory,
-    InvalidContext,
-    BuildProgramFailed,
-    NonexistentPlatform,
-    UnknownError(i32),
+    /// Where built programs are cached, if anywhere. Set it with use_cache().
+    pub cache: Option<KernelCache>,
+    /// What's been run, if profiling. Turn it on with use_profiling().
+    pub profiler: Option<Profiler>,
 }
 
-impl fmt::Display for ClError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::InvalidKernelName => {
-                write!(f, "Kernel name not found")
-            }
-            Self::InvalidProgram => {
-                write!(f, "Program not found")
-            }
-            Self::OutOfResources => {
-                write!(f, "Out of resources")
-            }
-            Self::UnknownError(unkwn) => {
-                write!(f, "Unknown error code: {unkwn}")
-            }
-            Self::InvalidContext => {
-                write!(f, "Invalid context")
-            }
-            Self::OutOfMemory => {
-                write!(f, "Out of memory")
-            }
-            Self::BuildProgramFailed => {
-                write!(f, "Failed to build program.")
-            }
-            Self::NonexistentPlatform => {
-                write!(f, "Platform out of range")
-            }
-            Self::InvalidArgIndex => {
-                write!(f, "Invalid arg index")
-            }
-        }
-    }
-}
-
-impl From<i32> for ClError {
-    fn from(code: i32) -> Self {
-        match code {
-            -42 => ClError::InvalidProgram,
-            -46 => ClError::InvalidKernelName,
-            -5 => ClError::OutOfResources,
-            -6 => ClError::OutOfMemory,
-            -34 => ClError::InvalidContext,
-            -11 => ClError::BuildProgramFailed,
-            -49 => ClError::InvalidArgIndex,
-            _ => ClError::UnknownError(code),
-        }
-    }
-}
-
-impl std::error::Error for ClError {}
-
 impl Env {
-    /// Make the kernel.
-    pub fn make_kernel(&mut self, name: &str) -> Result<(), ClError> {
-        unsafe {
-            let cname = CString::new(name).unwrap();
-            self.kernel = clCreateKernel(self.program, cname.as_ptr(), &mut self.err);
-            if self.kernel.is_null() {
-                Err(ClError::from(self.err))
-            } else {
-                Ok(())
-            }
-        }
-    }
-
-    /// new() creates a new Env.
+    /// new() creates a new Env, on the dev'th GPU of the plat'th platform.
+    /// To pick a device by what it can do instead, use select().
     pub fn new(plat: usize, dev: usize) -> Result<Self, ClError> {
         setup(plat, dev)
     }
-    /// program() programs and sets up the environment.
-    pub fn program(&mut self) -> Result<&mut Self, ClError> {
-        make_prog(self)?;
+    /// select() creates a new Env on the best device a DeviceSelector matches.
+    pub fn select(selector: &DeviceSelector) -> Result<Self, ClError> {
+        Self::from_device(&selector.select()?)
+    }
+    /// from_device() creates a new Env on a device from device::devices().
+    pub fn from_device(device: &Device) -> Result<Self, ClError> {
+        Self::from_devices(&[*device])
+    }
+    /// from_devices() creates a new Env whose context spans several devices, like an iGPU
+    /// and a dGPU. They all have to be on the same platform. The first is the one in use.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use obrah::build::BuildOptions;
+    /// use obrah::data::Buffer;
+    /// use obrah::device::{DeviceType, platforms};
+    /// use obrah::runtime::Env;
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let gpus = platforms()?[0].devices_of(DeviceType::Gpu)?;
+    ///     let mut env = Env::from_devices(&gpus)?;
+    ///     let mut kernel = env
+    ///         .use_kernel("examples/vecadd_kernel.cl")?
+    ///         .program(&BuildOptions::new())? // built for every GPU
+    ///         .make_kernel("vec_add")?;
+    ///
+    ///     let data = vec![1.0f32; 1024];
+    ///     let mut a = Buffer::new(&mut env, &data)?;
+    ///     let b = Buffer::new(&mut env, &data)?;
+    ///     a.to(&data, &mut env)?;
+    ///     kernel.setarg(&a, 0)?;
+    ///     kernel.setarg(&a, 1)?;
+    ///     kernel.setarg(&b, 2)?;
+    ///     kernel.run(&mut env, data.len())?; // on the first GPU
+    ///
+    ///     // move b over while the second GPU gets going
+    ///     let moved = b.migrate(&mut env, 1, &[])?;
+    ///     env.use_device(1)?;
+    ///     kernel.setarg(&b, 0)?;
+    ///     kernel.enqueue(&mut env, data.len(), &[&moved])?.wait()?; // on the second
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn from_devices(devices: &[Device]) -> Result<Self, ClError> {
+        let platform = devices
+            .first()
+            .ok_or(ClError::NoMatchingDevice {
+                rejected: Vec::new(),
+            })?
+            .platform;
+        if devices.iter().any(|d| d.platform != platform) {
+            return Err(ClError::MixedPlatforms);
+        }
+        let devices: Vec<_> = devices.iter().map(|d| d.device).collect();
+        create(platform, &devices)
+    }
+    /// use_device() makes launches and transfers go to the index'th device of the Env.
+    pub fn use_device(&mut self, index: usize) -> Result<&mut Self, ClError> {
+        let (&device, &queue) = self.devices.get(index).zip(self.queues.get(index)).ok_or(
+            ClError::NonexistentDevice {
+                index,
+                count: self.devices.len(),
+            },
+        )?;
+        self.device = device;
+        self.queue = queue;
         Ok(self)
     }
+    /// program() builds the kernel loaded by use_kernel() into a Program, with the given options.
+    /// For building from anything else, use Program::new().
+    pub fn program(&self, options: &BuildOptions) -> Result<Program, ClError> {
+        let source = self.kerncode.as_ref().ok_or(ClError::NoKernelLoaded)?;
+        Program::new(self, &[source], options)
+    }
     /// use_kernel() uses a kernel from a path.
     pub fn use_kernel(&mut self, path: &str) -> Result<&mut Self, Box<dyn std::error::Error>> {
         use_kernel(self, path)?;
         Ok(self)
     }
+    /// use_cache() caches built programs in a directory, so they don't have to be compiled
+    /// again next time. See KernelCache.
+    pub fn use_cache(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.cache = Some(KernelCache::new(dir));
+        self
+    }
+    /// use_profiling() makes the device time every command from now on, so Events have
+    /// times() and profile_report() can add them up. It waits for anything already queued.
+    /// Profiling can slow things down a little, so it's off unless asked for.
+    pub fn use_profiling(&mut self) -> Result<&mut Self, ClError> {
+        if self.profiler.is_none() {
+            let queues = make_queues(
+                self.context,
+                &self.devices,
+                CL_QUEUE_PROFILING_ENABLE.into(),
+            )?;
+            let index = self.devices.iter().position(|&d| d == self.device);
+            for queue in std::mem::replace(&mut self.queues, queues) {
+                unsafe {
+                    clFinish(queue);
+                    clReleaseCommandQueue(queue);
+                }
+            }
+            self.queue = self.queues[index.unwrap_or(0)];
+            self.profiler = Some(Profiler::default());
+        }
+        Ok(self)
+    }
+    /// profile_report() adds up the GPU time of everything run since use_profiling(),
+    /// per kernel and per transfer direction. See Profiler.
+    pub fn profile_report(&self) -> Result<ProfileReport, ClError> {
+        self.profiler
+            .as_ref()
+            .ok_or(ClError::ProfilingInfoNotAvailable)?
+            .report()
+    }
+    /// record() keeps a command's Event for profile_report(), if profiling.
+    pub(crate) fn record(&mut self, command: Command, event: &Event) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(command, event);
+        }
+    }
+    /// use_source() uses a kernel from a string, instead of a file.
+    /// Pair it with include_kernel!() to ship the kernel inside the binary.
+    pub fn use_source(&mut self, source: &str) -> &mut Self {
+        self.kerncode = Some(source.to_string());
+        self
+    }
 }
 
 impl Drop for Env {
@@ -161,74 +226,145 @@ fn setup(plat: usize, dev: usize) -> Result<Env, ClError> {
             std::ptr::null_mut(),
         );
 
-        let platform = platforms[plat];
-        let device = devices[dev];
+        create(platforms[plat], &[devices[dev]])
+    }
+}
 
+/// create() makes the context for some devices and a queue for each, and the Env holding them.
+fn create(platform: cl_platform_id, devices: &[cl_device_id]) -> Result<Env, ClError> {
+    unsafe {
         let mut err: cl_int = 0;
 
         let context = clCreateContext(
             std::ptr::null_mut(),
-            1,
-            &device,
+            devices.len() as cl_uint,
+            devices.as_ptr(),
             None,
             std::ptr::null_mut(),
             &mut err,
         );
 
-        if err != 0 {
-            return Err(ClError::from(err));
-        }
-
-        let queue = clCreateCommandQueue(context, device, 0, &mut err);
+        check("clCreateContext", err)?;
 
-        if err != 0 {
-            return Err(ClError::from(err));
-        }
+        let queues = match make_queues(context, devices, 0) {
+            Ok(queues) => queues,
+            Err(e) => {
+                clReleaseContext(context);
+                return Err(e);
+            }
+        };
 
         Ok(Env {
             platform,
-            device,
+            device: devices[0],
             context,
-            queue,
-            program: std::ptr::null_mut(),
-            kernel: std::ptr::null_mut(),
+            queue: queues[0],
+            devices: devices.to_vec(),
+            queues,
             kerncode: None,
-            err,
+            cache: None,
+            profiler: None,
         })
     }
 }
 
-// The make_prog() function uses the kernel and initialisations to make the actual OpenCL programs.
-fn make_prog(env: &mut Env) -> Result<(), ClError> {
-    unsafe {
-        let source = env
-            .kerncode
-            .as_ref()
-            .expect("Kernel not loaded! Call use_kernel first.");
+/// make_queue() creates a command queue on a device.
+fn make_queue(
+    context: cl_context,
+    device: cl_device_id,
+    properties: cl_command_queue_properties,
+) -> Result<cl_command_queue, ClError> {
+    let mut err = 0;
+    let queue = unsafe { clCreateCommandQueue(context, device, properties, &mut err) };
+    check("clCreateCommandQueue", err)?;
+    Ok(queue)
+}
 
-        let c_source = CString::new(source.as_str()).unwrap();
-        let mut src_ptr = c_source.as_ptr();
-        let program =
-            clCreateProgramWithSource(env.context, 1, &mut src_ptr, std::ptr::null(), &mut env.err);
-        if program.is_null() {
-            Err(ClError::from(env.err))
-        } else {
-            let builderr = clBuildProgram(
-                program,
-                1,
-                &env.device,
-                std::ptr::null_mut(),
-                None,
-                std::ptr::null_mut(),
-            );
-            if builderr != 0 {
-                Err(ClError::from(builderr))
-            } else {
-                env.program = program;
-                Ok(())
+/// make_queues() creates a command queue on each device, or none if one fails.
+fn make_queues(
+    context: cl_context,
+    devices: &[cl_device_id],
+    properties: cl_command_queue_properties,
+) -> Result<Vec<cl_command_queue>, ClError> {
+    let mut queues = Vec::with_capacity(devices.len());
+    for &device in devices {
+        match make_queue(context, device, properties) {
+            Ok(queue) => queues.push(queue),
+            Err(e) => {
+                for queue in queues {
+                    unsafe {
+                        clReleaseCommandQueue(queue);
+                    }
+                }
+                return Err(e);
             }
         }
     }
+    Ok(queues)
+}
+
+/// device_value() gets a piece of fixed-size info about a device, like its memory size.
+/// It's T's default if the device doesn't know it.
+pub(crate) fn device_value<T: Default>(device: cl_device_id, param: cl_device_info) -> T {
+    let mut value = T::default();
+    unsafe {
+        clGetDeviceInfo(
+            device,
+            param,
+            std::mem::size_of::<T>(),
+            &mut value as *mut T as *mut _,
+            std::ptr::null_mut(),
+        );
+    }
+    value
+}
+
+/// work_item_sizes() gets the most work-items a work-group can have in each dimension, one
+/// entry per dimension the device has.
+pub(crate) fn work_item_sizes(device: cl_device_id) -> Result<Vec<usize>, ClError> {
+    let dims: cl_uint = device_value(device, CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS);
+    let mut sizes = vec![0usize; dims as usize];
+    unsafe {
+        let err = clGetDeviceInfo(
+            device,
+            CL_DEVICE_MAX_WORK_ITEM_SIZES,
+            std::mem::size_of_val(sizes.as_slice()),
+            sizes.as_mut_ptr() as *mut _,
+            std::ptr::null_mut(),
+        );
+        check("clGetDeviceInfo", err)?;
+    }
+    Ok(sizes)
+}
+
+/// device_name() gets the name of a device, for error messages.
+pub(crate) fn device_name(device: cl_device_id) -> String {
+    device_string(device, CL_DEVICE_NAME)
+}
+
+/// device_string() gets a piece of string info about a device, like its name or driver version.
+pub(crate) fn device_string(device: cl_device_id, param: cl_device_info) -> String {
+    unsafe {
+        let mut size = 0;
+        clGetDeviceInfo(device, param, 0, std::ptr::null_mut(), &mut size);
+        let mut buf = vec![0u8; size];
+        clGetDeviceInfo(
+            device,
+            param,
+            size,
+            buf.as_mut_ptr() as *mut _,
+            std::ptr::null_mut(),
+        );
+        info_string(buf)
+    }
+}
+
+/// info_string() turns the nul-terminated bytes OpenCL hands back into a String.
+pub(crate) fn info_string(mut buf: Vec<u8>) -> String {
+    while buf.last() == Some(&0) {
+        buf.pop();
+    }
+    String::from_utf8_lossy(&buf).into_owned()
 }
 
 /// use_kernel() loads the kernel from a path.
@@ -252,9 +388,9 @@ fn use_kernel(env: &mut Env, path: &str) -> Result<(), Box<dyn std::error::Error
 /// ```
 fn cleanup(env: &mut Env) {
     unsafe {
-        clReleaseKernel(env.kernel);
-        clReleaseProgram(env.program);
-        clReleaseCommandQueue(env.queue);
+        for &queue in &env.queues {
+            clReleaseCommandQueue(queue);
+        }
         clReleaseContext(env.context);
     }
 }
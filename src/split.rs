@@ -0,0 +1,390 @@
+use crate::data::{Buffer, flush};
+use crate::error::check;
+use crate::event::{Event, WaitList};
+use crate::kernel::{Kernel, NdRange, enqueue_kernel};
+use crate::profile::{Command, Direction};
+use crate::runtime::{ClError, Env, device_value};
+use obwio::*;
+
+/// Split spreads one launch over every device of a multi-device Env (see Env::from_devices()).
+/// A 1-D range is cut along x and a 2-D range into bands of rows, and each device gets a
+/// share in proportion to its weight. Weights come from the devices' compute units and clock,
+/// or, with measured(), from how fast each device actually went last time.
+///
+/// run() gives each device its own sub-buffer of the output, so they don't write over each
+/// other, and reads every slice back into one host slice. A slice is run with a global offset,
+/// and its sub-buffer starts at that slice's first element, so the kernel has to index the
+/// output relative to the slice, e.g. with `get_global_id(1) - get_global_offset(1)` for rows.
+///
+/// # Examples
+///
+/// ```rust
+/// use obrah::kernel::NdRange;
+/// use obrah::split::Split;
+///
+/// // one device three times as fast as the other
+/// let split = Split::from_weights(vec![3.0, 1.0]);
+/// let slices = split.slices(NdRange::d2(1920, 1080));
+/// assert_eq!(slices[0], NdRange::d2(1920, 810).offset([0, 0]));
+/// assert_eq!(slices[1], NdRange::d2(1920, 270).offset([0, 810]));
+/// ```
+///
+/// ```rust,no_run
+/// use obrah::build::BuildOptions;
+/// use obrah::data::Buffer;
+/// use obrah::device::{DeviceType, platforms};
+/// use obrah::runtime::Env;
+/// use obrah::split::Split;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let gpus = platforms()?[0].devices_of(DeviceType::Gpu)?;
+///     let mut env = Env::from_devices(&gpus)?;
+///     let mut kernel = env
+///         .use_profiling()? // so measured() can time each device
+///         .use_kernel("examples/raytrace_kernel.cl")?
+///         .program(&BuildOptions::new())?
+///         .make_kernel("raytrace")?;
+///
+///     let mut image = vec![0.0f32; 1920 * 1080 * 4];
+///     let image_buf = Buffer::new(&mut env, &image)?;
+///     // ... set the other arguments ...
+///
+///     let mut split = Split::measured(&env);
+///     for _frame in 0..60 {
+///         split.run(&mut env, &mut kernel, (1920, 1080), (0, &image_buf), &mut image)?;
+///     }
+///     println!("weights settled at {:?}", split.weights());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Split {
+    weights: Vec<f64>,
+    measured: bool,
+}
+
+impl Split {
+    /// Every device gets the same share.
+    pub fn even(env: &Env) -> Self {
+        Self::from_weights(vec![1.0; env.devices.len()])
+    }
+    /// Shares go by compute units times clock speed.
+    pub fn compute_units(env: &Env) -> Self {
+        let weights = env
+            .devices
+            .iter()
+            .map(|&device| {
+                let units: cl_uint = device_value(device, CL_DEVICE_MAX_COMPUTE_UNITS);
+                let clock: cl_uint = device_value(device, CL_DEVICE_MAX_CLOCK_FREQUENCY);
+                (units.max(1) as f64) * (clock.max(1) as f64)
+            })
+            .collect();
+        Self::from_weights(weights)
+    }
+    /// Start by compute units, then after every run() reweigh by how many work-items each
+    /// device got through per second. Timing needs Env::use_profiling(); without it the
+    /// weights stay as they are.
+    pub fn measured(env: &Env) -> Self {
+        Split {
+            measured: true,
+            ..Self::compute_units(env)
+        }
+    }
+    /// Shares go by the given weights, one per device of the Env, in the same order.
+    pub fn from_weights(weights: Vec<f64>) -> Self {
+        Split {
+            weights,
+            measured: false,
+        }
+    }
+    /// The weight of each device.
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+
+    /// Cut a range into one slice per device. Slices keep the range's local size, and are cut
+    /// on multiples of it. A device can get an empty slice, with a global size of 0.
+    pub fn slices(&self, range: impl Into<NdRange>) -> Vec<NdRange> {
+        let range = range.into();
+        let granularity = split_local(&range);
+        self.partition(&range, granularity)
+    }
+
+    /// Run a kernel over a range, split across the devices, and gather the output into out.
+    /// output is the kernel's output argument, as (arg index, buffer); out has to be as long
+    /// as the buffer, and the buffer a whole number of elements per row (or per work-item,
+    /// for 1-D). The argument is set back to the whole buffer afterwards.
+    pub fn run<T>(
+        &mut self,
+        env: &mut Env,
+        kernel: &mut Kernel,
+        range: impl Into<NdRange>,
+        output: (usize, &Buffer<T>),
+        out: &mut [T],
+    ) -> Result<(), ClError>
+    where
+        T: Copy + 'static,
+    {
+        let range = range.into();
+        let (arg, buffer) = output;
+        if self.weights.len() != env.devices.len() {
+            return Err(ClError::WeightCount {
+                weights: self.weights.len(),
+                devices: env.devices.len(),
+            });
+        }
+        if range.dims() > 2 {
+            return Err(ClError::SplitDimensions { dims: range.dims() });
+        }
+        let len = buffer.len();
+        let extent = *range.global().last().unwrap();
+        if out.len() != len {
+            return Err(ClError::LengthMismatch {
+                expected: len,
+                found: out.len(),
+            });
+        }
+        if extent == 0 || len % extent != 0 {
+            return Err(ClError::UnevenSplit { len, rows: extent });
+        }
+        let per_row = len / extent;
+        let row_bytes = per_row * std::mem::size_of::<T>();
+
+        // sub-buffers have to start on the devices' base address alignment
+        let align = env
+            .devices
+            .iter()
+            .map(|&d| (device_value::<cl_uint>(d, CL_DEVICE_MEM_BASE_ADDR_ALIGN) / 8).max(1))
+            .max()
+            .unwrap_or(1) as usize;
+        let align_rows = align / gcd(align, row_bytes.max(1));
+        let granularity = lcm(align_rows, split_local(&range));
+        let slices = self.partition(&range, granularity);
+
+        let active = env.device;
+        let result = run_slices(env, kernel, &slices, arg, buffer, out, per_row);
+        // put things back the way they were, even if a slice failed
+        if let Some(index) = env.devices.iter().position(|&d| d == active) {
+            env.use_device(index)?;
+        }
+        kernel.setarg(buffer, arg)?;
+        let events = result?;
+
+        if self.measured {
+            self.reweigh(&slices, &events);
+        }
+        Ok(())
+    }
+
+    /// reweigh() sets the weights to each device's work-items per second, from the kernel
+    /// events of the last run. Devices that weren't timed, like ones with an empty slice,
+    /// keep their share.
+    fn reweigh(&mut self, slices: &[NdRange], events: &[Option<Event>]) {
+        let rates: Vec<Option<f64>> = slices
+            .iter()
+            .zip(events)
+            .map(|(slice, event)| {
+                let secs = event.as_ref()?.times().ok()?.duration().as_secs_f64();
+                let items = slice.global().iter().product::<usize>() as f64;
+                (secs > 0.0).then(|| items / secs)
+            })
+            .collect();
+        // the old weights can be in any unit, so scale the untimed ones to match the rates
+        let (old, new) = self
+            .weights
+            .iter()
+            .zip(&rates)
+            .filter_map(|(w, rate)| rate.map(|r| (w, r)))
+            .fold((0.0, 0.0), |(old, new), (w, r)| (old + w, new + r));
+        if old <= 0.0 {
+            return;
+        }
+        for (weight, rate) in self.weights.iter_mut().zip(rates) {
+            *weight = rate.unwrap_or(*weight * new / old);
+        }
+    }
+
+    /// partition() cuts the last dimension of a range by weight, on multiples of granularity.
+    fn partition(&self, range: &NdRange, granularity: usize) -> Vec<NdRange> {
+        let dims = range.dims();
+        let extent = range.global()[dims - 1];
+        let base = range.global_offset().map_or(0, |offset| offset[dims - 1]);
+        let total: f64 = self.weights.iter().sum();
+
+        let mut slices = Vec::with_capacity(self.weights.len());
+        let mut start = 0;
+        let mut cumulative = 0.0;
+        for (i, weight) in self.weights.iter().enumerate() {
+            cumulative += weight;
+            let end = if i + 1 == self.weights.len() || total <= 0.0 {
+                extent
+            } else {
+                let share = extent as f64 * cumulative / total / granularity as f64;
+                (share.round() as usize * granularity).clamp(start, extent)
+            };
+            slices.push(slice(range, base + start, end - start));
+            start = end;
+        }
+        slices
+    }
+}
+
+/// run_slices() starts every slice on its device, then waits for them all. It gives back each
+/// device's kernel event, or None for devices that had nothing to do.
+fn run_slices<T>(
+    env: &mut Env,
+    kernel: &mut Kernel,
+    slices: &[NdRange],
+    arg: usize,
+    buffer: &Buffer<T>,
+    out: &mut [T],
+    per_row: usize,
+) -> Result<Vec<Option<Event>>, ClError>
+where
+    T: Copy + 'static,
+{
+    let dims = slices.first().map_or(1, NdRange::dims);
+    let base = slices
+        .first()
+        .and_then(|s| s.global_offset())
+        .map_or(0, |offset| offset[dims - 1]);
+    let mut sub_buffers = Vec::new();
+    let mut kernels = Vec::new();
+    let mut reads = Vec::new();
+    let mut rest = out;
+    let result: Result<(), ClError> = (|| {
+        for (i, slice) in slices.iter().enumerate() {
+            let rows = slice.global()[dims - 1];
+            if rows == 0 {
+                kernels.push(None);
+                continue;
+            }
+            let first = slice.global_offset().map_or(0, |offset| offset[dims - 1]) - base;
+            let (chunk, tail) = std::mem::take(&mut rest).split_at_mut(rows * per_row);
+            rest = tail;
+
+            let size = std::mem::size_of_val(chunk);
+            let sub = sub_buffer(buffer, first * per_row * std::mem::size_of::<T>(), size)?;
+            sub_buffers.push(sub);
+            set_mem_arg(kernel, arg, sub, size)?;
+            env.use_device(i)?;
+            let event = enqueue_kernel(env, kernel, *slice, &[])?;
+            reads.push(read(env, sub, chunk, &event)?);
+            kernels.push(Some(event));
+        }
+        for read in &reads {
+            read.wait()?;
+        }
+        Ok(())
+    })();
+    // every read has to be done before out is given back, even when something failed
+    for read in &reads {
+        let _ = read.wait();
+    }
+    for sub in sub_buffers {
+        unsafe {
+            clReleaseMemObject(sub);
+        }
+    }
+    result.map(|()| kernels)
+}
+
+/// slice() makes the part of a range from start to start + len in its last dimension.
+fn slice(range: &NdRange, start: usize, len: usize) -> NdRange {
+    let global = range.global();
+    let offset = range.global_offset();
+    let local = range.local_size();
+    let mut slice = if global.len() == 1 {
+        NdRange::d1(len).offset([start])
+    } else {
+        let x = offset.map_or(0, |offset| offset[0]);
+        NdRange::d2(global[0], len).offset([x, start])
+    };
+    if let Some(local) = local {
+        slice = match *local {
+            [x] => slice.local([x]),
+            [x, y] => slice.local([x, y]),
+            _ => slice,
+        };
+    }
+    slice
+}
+
+/// split_local() is the local size along the dimension that's split, so slices stay whole
+/// work-groups.
+fn split_local(range: &NdRange) -> usize {
+    range
+        .local_size()
+        .and_then(|local| local.last().copied())
+        .unwrap_or(1)
+        .max(1)
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn lcm(a: usize, b: usize) -> usize {
+    a / gcd(a, b) * b
+}
+
+/// sub_buffer() makes a sub-buffer of size bytes, origin bytes into a buffer.
+fn sub_buffer<T>(buffer: &Buffer<T>, origin: usize, size: usize) -> Result<cl_mem, ClError>
+where
+    T: Clone + 'static,
+{
+    let region = _cl_buffer_region { origin, size };
+    let mut err = 0;
+    let sub = unsafe {
+        clCreateSubBuffer(
+            buffer.buffer,
+            0,
+            CL_BUFFER_CREATE_TYPE_REGION,
+            &region as *const _ as *const _,
+            &mut err,
+        )
+    };
+    check("clCreateSubBuffer", err).map_err(|e| e.with_size(size))?;
+    Ok(sub)
+}
+
+/// set_mem_arg() sets a kernel argument to a bare cl_mem, like a sub-buffer.
+fn set_mem_arg(kernel: &mut Kernel, arg: usize, mem: cl_mem, size: usize) -> Result<(), ClError> {
+    let err = unsafe {
+        clSetKernelArg(
+            kernel.kernel,
+            arg as cl_uint,
+            std::mem::size_of::<cl_mem>(),
+            &mem as *const cl_mem as *const _,
+        )
+    };
+    check("clSetKernelArg", err)
+        .map_err(|e| e.with_kernel(&kernel.name).with_arg(arg).with_size(size))
+}
+
+/// read() reads a sub-buffer into its part of the output once the kernel is done, without
+/// blocking, on the Env's queue in use. The caller has to wait for it before letting go of
+/// data; if it fails after being queued, it has already waited.
+fn read<T>(env: &mut Env, mem: cl_mem, data: &mut [T], kernel: &Event) -> Result<Event, ClError> {
+    let after = WaitList::new(&[kernel]);
+    let size = std::mem::size_of_val(data);
+    let mut event = std::ptr::null_mut();
+    let err = unsafe {
+        clEnqueueReadBuffer(
+            env.queue,
+            mem,
+            CL_FALSE,
+            0,
+            size,
+            data.as_mut_ptr() as *mut _,
+            after.len(),
+            after.as_ptr(),
+            &mut event,
+        )
+    };
+    check("clEnqueueReadBuffer", err).map_err(|e| e.with_size(size))?;
+    let event = Event::new(event);
+    flush(env.queue, &event)?;
+    env.record(Command::Transfer(Direction::Read, size), &event);
+    Ok(event)
+}